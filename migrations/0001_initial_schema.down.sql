@@ -0,0 +1,9 @@
+DROP TABLE IF EXISTS last_read;
+DROP TABLE IF EXISTS message_reactions;
+DROP TABLE IF EXISTS message_mentions;
+DROP TABLE IF EXISTS direct_messages;
+DROP TABLE IF EXISTS messages;
+DROP TABLE IF EXISTS rooms;
+DROP TABLE IF EXISTS password_resets;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS users;
@@ -0,0 +1,4 @@
+ALTER TABLE messages
+    DROP CONSTRAINT IF EXISTS messages_username_fkey,
+    ADD CONSTRAINT messages_username_fkey
+        FOREIGN KEY (username) REFERENCES users(username);
@@ -0,0 +1,5 @@
+-- messages keep a copy of the author's username; let renames carry over to it
+ALTER TABLE messages
+    DROP CONSTRAINT IF EXISTS messages_username_fkey,
+    ADD CONSTRAINT messages_username_fkey
+        FOREIGN KEY (username) REFERENCES users(username) ON UPDATE CASCADE;
@@ -0,0 +1,2 @@
+-- users can't sign in or use their sessions until this has passed
+ALTER TABLE users ADD COLUMN IF NOT EXISTS banned_until TIMESTAMP WITH TIME ZONE;
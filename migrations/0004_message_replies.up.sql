@@ -0,0 +1,3 @@
+-- a reply points at the message it answers; replies outlive a purged parent
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS parent_id INTEGER REFERENCES messages(id) ON DELETE SET NULL;
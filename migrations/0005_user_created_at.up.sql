@@ -0,0 +1,3 @@
+-- accounts that predate this column get the time of the migration
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,9 @@
+-- emails are stored lowercase from now on and looked up with LOWER(email), so
+-- older mixed-case rows keep working without rewriting every foreign key.
+-- this fails if two existing accounts differ only by case; merge or rename
+-- one of them first
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (LOWER(email));
+
+-- RegexValidator only admits lowercase usernames; this keeps the database
+-- honest about it too
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower_key ON users (LOWER(username));
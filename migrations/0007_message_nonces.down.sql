@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS messages_email_nonce_key;
+ALTER TABLE messages DROP COLUMN IF EXISTS nonce;
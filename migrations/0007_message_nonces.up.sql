@@ -0,0 +1,4 @@
+-- client-generated key that makes resending a message safe; NULLs don't
+-- collide, so messages sent without one are unaffected
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS nonce VARCHAR(64);
+CREATE UNIQUE INDEX IF NOT EXISTS messages_email_nonce_key ON messages (email, nonce);
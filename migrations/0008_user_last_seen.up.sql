@@ -0,0 +1,2 @@
+-- null until the user first opens a websocket
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS messages_room_time_id_idx;
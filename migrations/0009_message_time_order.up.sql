@@ -0,0 +1,2 @@
+-- GET /messages pages through a room by (time, id)
+CREATE INDEX IF NOT EXISTS messages_room_time_id_idx ON messages (room_id, time DESC, id DESC);
@@ -0,0 +1,10 @@
+-- single-use signup codes for REGISTRATION_INVITES; only the hash is kept,
+-- like password reset tokens
+CREATE TABLE IF NOT EXISTS invite_codes (
+    id SERIAL PRIMARY KEY,
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_by VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    used_by VARCHAR(255),
+    used_at TIMESTAMP WITH TIME ZONE
+);
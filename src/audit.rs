@@ -0,0 +1,61 @@
+// libs
+use sqlx::PgPool;
+
+// security-relevant events are written to audit_log so operators can look
+// into an incident after the fact. a failed write is logged but never fails
+// the request it describes
+
+// structs
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    LoginSuccess,
+    LoginFailure,
+    Logout,
+    LogoutAll,
+    PasswordReset,
+    PasswordChange,
+    MessageDelete,
+    UserBan,
+    AccountDelete,
+    InviteCreate,
+}
+
+// mods
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::LoginSuccess => "login_success",
+            Action::LoginFailure => "login_failure",
+            Action::Logout => "logout",
+            Action::LogoutAll => "logout_all",
+            Action::PasswordReset => "password_reset",
+            Action::PasswordChange => "password_change",
+            Action::MessageDelete => "message_delete",
+            Action::UserBan => "user_ban",
+            Action::AccountDelete => "account_delete",
+            Action::InviteCreate => "invite_create",
+        }
+    }
+}
+
+// `actor` is the email of whoever acted (the attempted one for failed
+// logins), `target` whatever they acted on
+pub async fn record(
+    pool: &PgPool,
+    actor: Option<&str>,
+    action: Action,
+    target: Option<&str>,
+    ip: &str,
+) {
+    if let Err(e) =
+        sqlx::query("INSERT INTO audit_log (actor, action, target, ip) VALUES ($1, $2, $3, $4)")
+            .bind(actor)
+            .bind(action.as_str())
+            .bind(target)
+            .bind(ip)
+            .execute(pool)
+            .await
+    {
+        tracing::error!(error = %e, action = action.as_str(), ?actor, ?target, "failed to write audit log");
+    }
+}
@@ -1,11 +1,66 @@
-use sqlx::postgres::PgPoolOptions;
-use std::env;
-
-pub async fn create_pool() -> sqlx::Pool<sqlx::Postgres> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create database connection pool")
-}
\ No newline at end of file
+use crate::config::Config;
+use log::LevelFilter;
+use sqlx::ConnectOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use std::time::{Duration, Instant};
+
+// postgres may still be starting when the app boots alongside it, so the first
+// connection is retried with exponential backoff before giving up
+const CONNECT_RETRY_BUDGET: Duration = Duration::from_secs(30);
+const CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const CONNECT_MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+pub async fn create_pool(config: &Config) -> sqlx::Pool<sqlx::Postgres> {
+    let settings = &config.pool;
+
+    let options = PgPoolOptions::new()
+        .max_connections(settings.max_connections)
+        .min_connections(settings.min_connections)
+        .acquire_timeout(settings.acquire_timeout)
+        .idle_timeout(settings.idle_timeout);
+
+    // sqlx times every statement itself and, past the threshold, emits a
+    // warning on the `sqlx::query` target with a summary of the sql, the
+    // elapsed time and the row counts. queries run through time_query log it
+    // inside a `query{operation=...}` span, which names the call site
+    let connect_options: PgConnectOptions = config
+        .database_url
+        .parse()
+        .unwrap_or_else(|e| panic!("Invalid DATABASE_URL: {}", e));
+    let connect_options = match config.slow_query_threshold {
+        Some(threshold) => connect_options.log_slow_statements(LevelFilter::Warn, threshold),
+        None => connect_options.log_slow_statements(LevelFilter::Off, Duration::ZERO),
+    };
+
+    let started = Instant::now();
+    let mut backoff = CONNECT_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match options.clone().connect_with(connect_options.clone()).await {
+            Ok(pool) => return pool,
+            Err(e) if started.elapsed() + backoff < CONNECT_RETRY_BUDGET => {
+                tracing::warn!(
+                    error = %e,
+                    attempt,
+                    retry_in_ms = backoff.as_millis() as u64,
+                    "database connection failed, retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(CONNECT_MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => panic!("Failed to create database connection pool: {}", e),
+        }
+    }
+}
+
+// the constraint behind a unique violation (postgres code 23505), if that's
+// what `err` is
+pub fn unique_violation(err: &sqlx::Error) -> Option<&str> {
+    match err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
+            db_err.constraint()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,174 @@
+// libs
+use crate::routes::chat::{ChatMessage, OutgoingMessage};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+// with MULTI_INSTANCE on, message changes are announced over postgres
+// NOTIFY so instances behind the same load balancer can hand them to their
+// own websockets. only ids travel (NOTIFY payloads are capped at 8000 bytes),
+// the receiving side reads the row back
+
+const CHANNEL: &str = "kutter_messages";
+const RELISTEN_DELAY: Duration = Duration::from_secs(5);
+
+// tells this instance's own notifications apart from everyone else's
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| Uuid::new_v4().to_string());
+
+// structs
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Event {
+    New { message_id: i32 },
+    Edit { message_id: i32 },
+    Delete { room_id: i32, message_id: i32 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Notification {
+    origin: String,
+    #[serde(flatten)]
+    event: Event,
+}
+
+// mods
+// None for frames that only matter to this instance's own sockets
+fn event_for(msg: &OutgoingMessage) -> Option<Event> {
+    match msg {
+        OutgoingMessage::NewMessage(msg) => msg.id.map(|message_id| Event::New { message_id }),
+        OutgoingMessage::Edit { message_id, .. } => Some(Event::Edit {
+            message_id: *message_id,
+        }),
+        OutgoingMessage::Delete {
+            room_id,
+            message_id,
+        } => Some(Event::Delete {
+            room_id: *room_id,
+            message_id: *message_id,
+        }),
+        _ => None,
+    }
+}
+
+pub async fn publish(pool: &PgPool, msg: &OutgoingMessage) {
+    let Some(event) = event_for(msg) else {
+        return;
+    };
+    let payload = serde_json::to_string(&Notification {
+        origin: INSTANCE_ID.clone(),
+        event,
+    })
+    .unwrap();
+
+    if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(&payload)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!(error = %e, payload, "failed to publish message to other instances");
+    }
+}
+
+// the frames `event` stands for, built from the database; empty when the
+// message is already gone again
+async fn rebuild(pool: &PgPool, event: Event) -> Result<Vec<OutgoingMessage>, sqlx::Error> {
+    match event {
+        Event::New { message_id } => {
+            let Some(msg) = sqlx::query_as::<_, ChatMessage>(
+                "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages WHERE id = $1 AND deleted_at IS NULL",
+            )
+            .bind(message_id)
+            .fetch_optional(pool)
+            .await?
+            else {
+                return Ok(Vec::new());
+            };
+
+            let mentioned = sqlx::query_scalar::<_, String>(
+                "SELECT u.username FROM message_mentions m
+                JOIN users u ON u.email = m.email
+                WHERE m.message_id = $1
+                ORDER BY u.username",
+            )
+            .bind(message_id)
+            .fetch_all(pool)
+            .await?;
+
+            let room_id = msg.room_id;
+            let mut frames = vec![OutgoingMessage::NewMessage(msg)];
+            if !mentioned.is_empty() {
+                frames.push(OutgoingMessage::Mention {
+                    room_id,
+                    message_id,
+                    mentioned,
+                });
+            }
+            Ok(frames)
+        }
+        Event::Edit { message_id } => {
+            let edit = sqlx::query_as::<_, (i32, String, DateTime<Utc>)>(
+                "SELECT room_id, message, edited_at FROM messages
+                WHERE id = $1 AND deleted_at IS NULL AND edited_at IS NOT NULL",
+            )
+            .bind(message_id)
+            .fetch_optional(pool)
+            .await?;
+            Ok(edit
+                .map(|(room_id, message, edited_at)| OutgoingMessage::Edit {
+                    room_id,
+                    message_id,
+                    message,
+                    edited_at,
+                })
+                .into_iter()
+                .collect())
+        }
+        Event::Delete {
+            room_id,
+            message_id,
+        } => Ok(vec![OutgoingMessage::Delete {
+            room_id,
+            message_id,
+        }]),
+    }
+}
+
+async fn listen(pool: &PgPool, tx: &broadcast::Sender<OutgoingMessage>) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    tracing::info!(instance = %*INSTANCE_ID, "listening for messages from other instances");
+
+    loop {
+        let notification = listener.recv().await?;
+        let event = match serde_json::from_str::<Notification>(notification.payload()) {
+            Ok(parsed) if parsed.origin == *INSTANCE_ID => continue,
+            Ok(parsed) => parsed.event,
+            Err(e) => {
+                tracing::warn!(error = %e, payload = notification.payload(), "ignoring malformed notification");
+                continue;
+            }
+        };
+        for frame in rebuild(pool, event).await? {
+            let _ = tx.send(frame);
+        }
+    }
+}
+
+// runs for the life of the process; changes announced while the listener is
+// reconnecting are missed, and clients recover them with resync
+pub fn spawn_listener(pool: PgPool, tx: broadcast::Sender<OutgoingMessage>) {
+    actix_rt::spawn(async move {
+        loop {
+            if let Err(e) = listen(&pool, &tx).await {
+                tracing::error!(error = %e, "multi-instance listener failed, reconnecting");
+            }
+            tokio::time::sleep(RELISTEN_DELAY).await;
+        }
+    });
+}
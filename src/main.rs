@@ -1,21 +1,35 @@
 use actix_files as fs;
-use actix_web::{App, HttpResponse, HttpServer, web};
+use actix_web::dev::{ServiceRequest, ServiceResponse, fn_service};
+use actix_web::{App, HttpResponse, HttpServer, http::Method, http::header, middleware, web};
+use config::PasswordPolicy;
 use dotenv::dotenv;
 use regex::Regex;
-use routes::chat::AppState;
+use routes::chat::{AppState, SHUTDOWN_GRACE};
+use serde::Serialize;
 use std::fs as std_fs;
 use std::sync::Arc;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::broadcast;
+use tracing_subscriber::EnvFilter;
 
+pub mod audit;
+pub mod config;
 pub mod db;
+pub mod fanout;
 pub mod middlewares;
+pub mod observer;
+pub mod retention;
 pub mod routes;
+pub mod shortcodes;
+pub mod utc;
+pub mod wordfilter;
 
 #[derive(Clone)]
 pub struct RegexValidator {
     pub email: Regex,
     pub username: Regex,
-    pub password: Regex,
+    pub password_policy: PasswordPolicy,
 }
 
 impl RegexValidator {
@@ -23,73 +37,229 @@ impl RegexValidator {
         Self {
             email: Regex::new(r"^[\w\.-]+@[\w\.-]+\.\w{2,}$").unwrap(),
             username: Regex::new(r"^[a-z0-9_-]{2,20}$").unwrap(),
-            password: Regex::new(r"^.{6,}$").unwrap(),
+            password_policy: config::get().password_policy,
         }
     }
 
     pub fn validate_password(&self, password: &str) -> bool {
-        if !self.password.is_match(password) {
-            return false;
+        self.validate_password_detailed(password).is_ok()
+    }
+
+    pub fn validate_password_detailed(&self, password: &str) -> Result<(), Vec<PasswordError>> {
+        let policy = &self.password_policy;
+        let mut errors = Vec::new();
+
+        if password.chars().count() < policy.min_length {
+            errors.push(PasswordError::TooShort);
+        }
+        if policy.require_uppercase && !password.chars().any(|c| c.is_ascii_uppercase()) {
+            errors.push(PasswordError::MissingUppercase);
+        }
+        if policy.require_lowercase && !password.chars().any(|c| c.is_ascii_lowercase()) {
+            errors.push(PasswordError::MissingLowercase);
+        }
+        if policy.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push(PasswordError::MissingDigit);
+        }
+        if policy.require_special && !password.chars().any(|c| !c.is_ascii_alphanumeric()) {
+            errors.push(PasswordError::MissingSpecial);
         }
 
-        let has_upper = password.chars().any(|c| c.is_ascii_uppercase());
-        let has_special = password.chars().any(|c| !c.is_ascii_alphanumeric());
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
 
-        has_upper && has_special
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordError {
+    TooShort,
+    MissingUppercase,
+    MissingLowercase,
+    MissingDigit,
+    MissingSpecial,
+}
+
+impl Default for RegexValidator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let pool = db::create_pool().await;
-    let (tx, _) = broadcast::channel(20);
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = match config::Config::from_env() {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            tracing::error!(error = %e, "configuration error");
+            std::process::exit(1);
+        }
+    };
+    config.log_summary();
+    let word_filter = match wordfilter::WordFilter::from_config(config) {
+        Ok(word_filter) => word_filter,
+        Err(e) => {
+            tracing::error!(error = %e, "configuration error");
+            std::process::exit(1);
+        }
+    };
+    let addr = (config.host.clone(), config.port);
+    let pool = db::create_pool(config).await;
+    let (tx, _) = broadcast::channel(config.broadcast_capacity);
     let regex_validator = RegexValidator::new();
+    let config_data = web::Data::new(config.clone());
+    let rate_limiters = web::Data::new(middlewares::RateLimiters::new());
 
-    let app_state = Arc::new(AppState {
-        db_pool: pool.clone(),
-        tx,
-    });
+    let app_state = Arc::new(AppState::new(pool.clone(), tx.clone(), word_filter));
+    let shutdown_state = app_state.clone();
 
-    middlewares::create_user_table(&pool)
+    sqlx::migrate!()
+        .run(&pool)
         .await
-        .expect("Failed to create table");
+        .expect("Failed to run migrations");
 
-    routes::chat::create_table(&pool)
-        .await
-        .expect("Failed to create table");
+    if config.multi_instance {
+        fanout::spawn_listener(pool.clone(), tx);
+    }
 
-    let maintenance_mode = false; // !!!!!
+    if let Some(days) = config.message_retention_days {
+        retention::spawn(pool.clone(), days);
+    }
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let app = App::new()
+            .app_data(config_data.clone())
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(app_state.clone()))
             .app_data(web::Data::new(regex_validator.clone()))
-            .wrap(middlewares::cors());
+            .app_data(rate_limiters.clone())
+            .app_data(middlewares::json_config())
+            .wrap(middleware::from_fn(middlewares::csrf_guard))
+            .wrap(middlewares::cors(&config.cors_origins))
+            .wrap(middleware::from_fn(middlewares::ip_filter))
+            .wrap(middleware::from_fn(middlewares::request_logger));
 
-        if maintenance_mode {
+        if config.maintenance_mode {
             app.default_service(web::route().to(|| async {
-                let html = std_fs::read_to_string("./static/maintain.html").unwrap_or_else(|_| {
+                let page = std::path::Path::new(&config::get().static_dir).join("maintain.html");
+                let html = std_fs::read_to_string(page).unwrap_or_else(|_| {
                     String::from("<h1>Site em manutenção</h1><p>Voltaremos em breve!</p>")
                 });
 
                 HttpResponse::ServiceUnavailable()
+                    .insert_header((header::RETRY_AFTER, "3600"))
                     .content_type("text/html")
                     .body(html)
             }))
         } else {
-            app.service(routes::auth::register)
-                .service(routes::auth::login)
-                .service(routes::auth::verify_user)
-                .service(routes::chat::ws_handler)
-                .service(routes::chat::get_messages)
-                .service(routes::auth::verify_email)
-                .service(routes::auth::logout)
-                .service(fs::Files::new("/", "./static").index_file("index.html"))
+            app.service(
+                web::scope(routes::API_PREFIX)
+                    .service(routes::auth::register)
+                    .service(routes::auth::login)
+                    .service(routes::auth::username_available)
+                    .service(routes::auth::password_policy)
+                    .service(routes::auth::verify_user)
+                    .service(routes::auth::me)
+                    .service(routes::chat::ws_handler)
+                    .service(routes::chat::events)
+                    .service(routes::chat::get_messages)
+                    .service(routes::chat::post_messages)
+                    .service(routes::chat::search_messages)
+                    .service(routes::chat::get_message)
+                    .service(routes::chat::get_online)
+                    .service(routes::chat::user_status)
+                    .service(routes::dm::get_direct_messages)
+                    .service(routes::uploads::upload)
+                    .service(routes::receipts::unread_count)
+                    .service(routes::auth::verify_email)
+                    .service(routes::auth::resend_verification)
+                    .service(routes::auth::logout)
+                    .service(routes::auth::logout_all)
+                    .service(routes::auth::delete_account)
+                    .service(routes::export::export_account)
+                    .service(routes::auth::change_username)
+                    .service(routes::admin::list_users)
+                    .service(routes::admin::ban_user)
+                    .service(routes::admin::audit_log)
+                    .service(routes::admin::set_motd)
+                    .service(routes::admin::create_invite)
+                    .service(routes::auth::refresh)
+                    .service(routes::auth::forgot_password)
+                    .service(routes::auth::reset_password)
+                    .service(routes::auth::change_password)
+                    .default_service(web::to(routes::api_fallback)),
+            )
+            // probes and scrapers stay unversioned
+            .service(routes::health::health_check)
+            .service(routes::metrics::metrics)
+            .service(static_files(&config.static_dir))
         }
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .bind(addr)?
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_GRACE.as_secs())
+    .run();
+
+    // close websockets ourselves before stopping the server, so clients get a
+    // reconnect hint instead of a dropped connection
+    let handle = server.handle();
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutdown signal received, closing websockets");
+        shutdown_state.shutdown(SHUTDOWN_GRACE).await;
+        handle.stop(true).await;
+    });
+
+    server.await
+}
+
+// no directory listings. unknown page urls get index.html so client-side
+// routes survive a reload, while unknown api and upload urls stay 404s
+fn static_files(dir: &str) -> fs::Files {
+    let index = std::path::Path::new(dir).join("index.html");
+    fs::Files::new("/", dir)
+        .index_file("index.html")
+        .default_handler(fn_service(move |req: ServiceRequest| {
+            let index = index.clone();
+            async move {
+                let path = req.path();
+                let spa_route = req.method() == Method::GET
+                    && !path.starts_with("/api/")
+                    && !path.starts_with("/uploads/");
+                if !spa_route {
+                    let res =
+                        HttpResponse::NotFound().json(serde_json::json!({ "error": "not_found" }));
+                    return Ok(req.into_response(res));
+                }
+                let (req, _) = req.into_parts();
+                let res = match fs::NamedFile::open_async(&index).await {
+                    Ok(file) => file.into_response(&req),
+                    Err(_) => HttpResponse::NotFound().finish(),
+                };
+                Ok(ServiceResponse::new(req, res))
+            }
+        }))
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -1,80 +1,665 @@
 // libs
+use crate::config;
+use crate::routes::API_PREFIX;
+use crate::routes::metrics::time_query;
 use actix_cors::Cors;
-use actix_web::http::header;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode, header};
+use actix_web::middleware::Next;
+use actix_web::web::JsonConfig;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
-use std::env;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use thiserror::Error;
 use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
 
 // structs
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    // missing from tokens minted before these claims were added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
     pub email: String,
+    #[serde(default)]
+    pub token_version: i32,
+    #[serde(default)]
+    pub role: String,
+}
+
+impl Claims {
+    pub fn is_moderator(&self) -> bool {
+        matches!(self.role.as_str(), "admin" | "moderator")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("missing token")]
+    Missing,
+    #[error("invalid token")]
+    Invalid,
+    #[error("expired token")]
+    Expired,
+    #[error("user is banned")]
+    Banned,
+    #[error("failed to verify session")]
+    Unavailable,
+}
+
+impl TokenError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenError::Missing => "missing_token",
+            TokenError::Invalid => "invalid_token",
+            TokenError::Expired => "expired_token",
+            TokenError::Banned => "banned",
+            TokenError::Unavailable => "internal_error",
+        }
+    }
+}
+
+impl ResponseError for TokenError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TokenError::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+            TokenError::Banned => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.code() }))
+    }
 }
 
 // middlewares
-pub async fn create_user_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            username VARCHAR(255) NOT NULL UNIQUE,
-            email VARCHAR(255) NOT NULL UNIQUE,
-            password VARCHAR(255) NOT NULL,
-            verified BOOLEAN NOT NULL DEFAULT FALSE,
-            verification_code VARCHAR(6)
-        )",
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
+// how far apart the clocks of the issuing and verifying servers may drift
+const CLOCK_SKEW_LEEWAY_SECS: u64 = 60;
+
+pub fn access_token_ttl() -> Duration {
+    Duration::seconds(config::get().jwt_expiry.as_secs() as i64)
 }
 
-pub fn generate_token(username: String, email: String) -> String {
-    let expiration = OffsetDateTime::now_utc() + Duration::days(1);
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+pub fn generate_token(username: String, email: String, token_version: i32, role: String) -> String {
+    let now = OffsetDateTime::now_utc();
+    let expiration = now + access_token_ttl();
 
     let claims = Claims {
         sub: username,
         exp: expiration.unix_timestamp() as usize,
-        email: email,
+        iat: Some(now.unix_timestamp() as usize),
+        nbf: Some(now.unix_timestamp() as usize),
+        email,
+        token_version,
+        role,
     };
 
-    let token = encode(
+    encode(
         &Header::default(),
         &claims,
-        &EncodingKey::from_secret(key.as_ref()),
+        &EncodingKey::from_secret(config::get().jwt_secret.as_bytes()),
     )
-    .unwrap();
-    token
+    .unwrap()
 }
 
-pub fn verify_token(token: String) -> Result<Claims, String> {
-    let key = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+// opaque random token, only its hash is ever stored
+pub fn generate_random_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// jsonwebtoken doesn't look at iat, so a token claiming to come from the future
+// is caught here. tokens without iat predate it and are still accepted, but
+// only while they lapse within one access token lifetime from now, so none of
+// them outlive the deploy that introduced iat by more than that
+fn issued_plausibly(claims: &Claims) -> bool {
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    match claims.iat {
+        Some(iat) => iat as u64 <= now + CLOCK_SKEW_LEEWAY_SECS,
+        None => claims.exp as u64 <= now + access_token_ttl().whole_seconds() as u64,
+    }
+}
+
+pub fn verify_token(token: String) -> Result<Claims, TokenError> {
     let mut validation = Validation::default();
-    validation.required_spec_claims.remove("verified");
+    // nbf is still checked whenever it's present
+    validation.set_required_spec_claims(&["exp"]);
+    validation.validate_nbf = true;
+    validation.leeway = CLOCK_SKEW_LEEWAY_SECS;
 
     match decode::<Claims>(
         &token,
-        &DecodingKey::from_secret(key.as_ref()),
-        &Validation::default(),
+        &DecodingKey::from_secret(config::get().jwt_secret.as_bytes()),
+        &validation,
     ) {
+        Ok(token_data) if !issued_plausibly(&token_data.claims) => Err(TokenError::Invalid),
         Ok(token_data) => Ok(token_data.claims),
+        Err(e) if *e.kind() == ErrorKind::ExpiredSignature => Err(TokenError::Expired),
+        Err(e) => {
+            tracing::debug!(error = %e, "token verification failed");
+            Err(TokenError::Invalid)
+        }
+    }
+}
+
+// on top of the signature check, compares the token's version with the user's
+// current one so that logout_all revokes every token minted before it. this
+// costs one extra query per authenticated request
+pub async fn verify_session(pool: &PgPool, token: String) -> Result<Claims, TokenError> {
+    let claims = verify_token(token)?;
+
+    let user = time_query(
+        "verify_session",
+        sqlx::query_as::<_, (i32, Option<DateTime<Utc>>)>(
+            "SELECT token_version, banned_until FROM users WHERE email = $1",
+        )
+        .bind(&claims.sub)
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "failed to look up token version");
+        TokenError::Unavailable
+    })?;
+
+    match user {
+        Some((_, Some(banned_until))) if banned_until > Utc::now() => Err(TokenError::Banned),
+        Some((version, _)) if version == claims.token_version => Ok(claims),
+        _ => Err(TokenError::Invalid),
+    }
+}
+
+// reads the token cookie and verifies the session behind it
+pub async fn authenticate(req: &HttpRequest, pool: &PgPool) -> Result<Claims, TokenError> {
+    let token = match req.cookie("token") {
+        Some(token) => token.value().to_string(),
+        None => return Err(TokenError::Missing),
+    };
+    verify_session(pool, token).await
+}
+
+// the marker a websocket client offers in Sec-WebSocket-Protocol right before
+// its token, e.g. `Sec-WebSocket-Protocol: bearer, <token>`
+pub const WS_BEARER_PROTOCOL: &str = "bearer";
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+fn subprotocol_token(req: &HttpRequest) -> Option<String> {
+    let mut protocols = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    protocols.find(|protocol| *protocol == WS_BEARER_PROTOCOL)?;
+    protocols.next().map(str::to_string)
+}
+
+// native clients can't always set cookies, so websocket upgrades also take
+// the token from `Authorization: Bearer` or the bearer subprotocol before
+// falling back to the cookie. the flag says whether the subprotocol was used,
+// in which case the server has to echo it back
+pub async fn authenticate_ws(
+    req: &HttpRequest,
+    pool: &PgPool,
+) -> Result<(Claims, bool), TokenError> {
+    if let Some(token) = bearer_token(req) {
+        return Ok((verify_session(pool, token).await?, false));
+    }
+    if let Some(token) = subprotocol_token(req) {
+        return Ok((verify_session(pool, token).await?, true));
+    }
+    Ok((authenticate(req, pool).await?, false))
+}
+
+// tags every request with an id, returned in X-Request-Id, and logs it with
+// its outcome and latency once the response is ready
+pub async fn request_logger(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = Uuid::new_v4().to_string();
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let started = Instant::now();
+
+    match next.call(req).await {
+        Ok(mut res) => {
+            tracing::info!(
+                request_id = %request_id,
+                method = %method,
+                path = %path,
+                status = res.status().as_u16(),
+                latency_ms = started.elapsed().as_millis() as u64,
+                "request completed"
+            );
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), value);
+            }
+            Ok(res)
+        }
         Err(e) => {
-            eprintln!("Token verification error: {:?}", e);
-            Err("Invalid token".to_string())
+            tracing::error!(
+                request_id = %request_id,
+                method = %method,
+                path = %path,
+                latency_ms = started.elapsed().as_millis() as u64,
+                error = %e,
+                "request failed"
+            );
+            Err(e)
+        }
+    }
+}
+
+pub const CSRF_COOKIE: &str = "csrf_token";
+const CSRF_HEADER: &str = "x-csrf-token";
+// re-entered password for sensitive reads like the account export
+pub const CONFIRM_PASSWORD_HEADER: &str = "x-confirm-password";
+
+// endpoints used before a session (and its csrf cookie) exists; refresh is
+// exempt too so sessions from before csrf cookies can pick one up
+const CSRF_EXEMPT_PATHS: &[&str] = &[
+    "/register",
+    "/login",
+    "/verify_email",
+    "/auth/resend-verification",
+    "/password/forgot",
+    "/password/reset",
+    "/refresh",
+];
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// double-submit check: unsafe requests must echo the csrf_token cookie in the
+// X-CSRF-Token header, which a cross-site page can't read to copy
+pub async fn csrf_guard<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    // browsers never attach an Authorization header on their own, so a
+    // request that authenticates with one and carries no session cookie
+    // can't be forged cross-site
+    let bearer_only =
+        req.headers().contains_key(header::AUTHORIZATION) && req.cookie("token").is_none();
+    let exempt = req
+        .path()
+        .strip_prefix(API_PREFIX)
+        .is_some_and(|path| CSRF_EXEMPT_PATHS.contains(&path));
+    if safe || bearer_only || exempt {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    let cookie = req.cookie(CSRF_COOKIE);
+    let header = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header))
+            if constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) =>
+        {
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        _ => {
+            let res = HttpResponse::Forbidden().json(json!({ "error": "csrf_mismatch" }));
+            Ok(req.into_response(res).map_into_right_body())
         }
     }
 }
 
-pub fn cors() -> Cors {
-    Cors::default()
-        .allowed_origin("http://localhost:8080")
-        .allowed_origin("http://localhost:1230")
+// with IP_ALLOWLIST or IP_DENYLIST set, requests from outside the allowed
+// blocks are refused before anything else looks at them. the address is the
+// one client_ip reports, so TRUSTED_PROXY_HOPS applies
+pub async fn ip_filter<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let config = config::get();
+    if config.ip_allowlist.is_empty() && config.ip_denylist.is_empty() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    let ip = client_ip(req.request());
+    let allowed = ip.parse::<IpAddr>().is_ok_and(|addr| {
+        !config.ip_denylist.iter().any(|block| block.contains(addr))
+            && (config.ip_allowlist.is_empty()
+                || config.ip_allowlist.iter().any(|block| block.contains(addr)))
+    });
+    if allowed {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    tracing::warn!(ip, path = %req.path(), "request from a blocked address");
+    let res = HttpResponse::Forbidden().json(json!({ "error": "ip_not_allowed" }));
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+// every JSON body is far smaller than this; anything bigger is refused before
+// it's parsed
+const JSON_BODY_LIMIT: usize = 16 * 1024;
+
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default()
+        .limit(JSON_BODY_LIMIT)
+        .error_handler(|err, _req| {
+            let detail = match &err {
+                JsonPayloadError::OverflowKnownLength { .. }
+                | JsonPayloadError::Overflow { .. } => {
+                    format!("body is larger than {} bytes", JSON_BODY_LIMIT)
+                }
+                JsonPayloadError::ContentType => {
+                    "Content-Type must be application/json".to_string()
+                }
+                err => err.to_string(),
+            };
+            let res = HttpResponse::BadRequest().json(json!({
+                "error": "invalid_body",
+                "detail": detail,
+            }));
+            InternalError::from_response(err, res).into()
+        })
+}
+
+pub fn cors(origins: &[String]) -> Cors {
+    origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
         .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
         .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
         .allowed_header(header::CONTENT_TYPE)
+        .allowed_header(HeaderName::from_static(CSRF_HEADER))
+        .allowed_header(HeaderName::from_static(CONFIRM_PASSWORD_HEADER))
         .max_age(3600)
 }
+
+// fixed-window request counter keyed by an arbitrary string (client ip, email, ...)
+pub struct RateLimiter {
+    max: u32,
+    window: std::time::Duration,
+    hits: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(max: u32, window: std::time::Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // counts a hit for `key`, returning how long to wait if it's over the limit
+    pub fn check(&self, key: &str) -> Result<(), std::time::Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+
+        if hits.len() > 10_000 {
+            hits.retain(|_, (_, started)| now.duration_since(*started) < self.window);
+        }
+
+        let entry = hits.entry(key.to_string()).or_insert((0, now));
+        if now.duration_since(entry.1) >= self.window {
+            *entry = (0, now);
+        }
+
+        if entry.0 >= self.max {
+            return Err(self.window - now.duration_since(entry.1));
+        }
+        entry.0 += 1;
+        Ok(())
+    }
+}
+
+// counts consecutive failed logins per key and locks the key out once
+// `max_failures` happen within `window`
+pub struct LoginLockout {
+    max_failures: u32,
+    window: std::time::Duration,
+    lockout: std::time::Duration,
+    failures: Mutex<HashMap<String, LoginFailures>>,
+}
+
+struct LoginFailures {
+    count: u32,
+    first: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl LoginLockout {
+    pub fn new(
+        max_failures: u32,
+        window: std::time::Duration,
+        lockout: std::time::Duration,
+    ) -> Self {
+        Self {
+            max_failures,
+            window,
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // returns how long until `key` may try again if it's locked
+    pub fn check(&self, key: &str) -> Result<(), std::time::Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        match failures.get(key).and_then(|f| f.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() > 10_000 {
+            failures.retain(|_, f| {
+                f.locked_until.is_some_and(|until| until > now)
+                    || now.duration_since(f.first) < self.window
+            });
+        }
+
+        let entry = failures.entry(key.to_string()).or_insert(LoginFailures {
+            count: 0,
+            first: now,
+            locked_until: None,
+        });
+        let expired = match entry.locked_until {
+            Some(until) => until <= now,
+            None => now.duration_since(entry.first) >= self.window,
+        };
+        if expired {
+            *entry = LoginFailures {
+                count: 0,
+                first: now,
+                locked_until: None,
+            };
+        }
+
+        entry.count += 1;
+        if entry.count >= self.max_failures {
+            entry.locked_until = Some(now + self.lockout);
+        }
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+pub struct RateLimiters {
+    pub auth: RateLimiter,
+    // POST /messages per user, matching the websocket's burst allowance
+    pub messages: RateLimiter,
+    pub username_check: RateLimiter,
+    pub resend_verification: RateLimiter,
+    pub login: LoginLockout,
+}
+
+impl RateLimiters {
+    pub fn new() -> Self {
+        let config = config::get();
+        Self {
+            auth: RateLimiter::new(config.auth_rate_limit, config.auth_rate_window),
+            messages: RateLimiter::new(5, std::time::Duration::from_secs(2)),
+            username_check: RateLimiter::new(30, std::time::Duration::from_secs(60)),
+            resend_verification: RateLimiter::new(1, std::time::Duration::from_secs(60)),
+            login: LoginLockout::new(
+                5,
+                std::time::Duration::from_secs(15 * 60),
+                std::time::Duration::from_secs(15 * 60),
+            ),
+        }
+    }
+}
+
+impl Default for RateLimiters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn client_ip(req: &HttpRequest) -> String {
+    let hops = config::get().trusted_proxy_hops;
+    if hops > 0
+        && let Some(ip) = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').nth(hops - 1))
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+    {
+        return ip.to_string();
+    }
+
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(claims: serde_json::Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(config::test().jwt_secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn now() -> i64 {
+        OffsetDateTime::now_utc().unix_timestamp()
+    }
+
+    #[test]
+    fn minted_token_verifies() {
+        config::test();
+        // call sites pass (email, username), which land in sub and email
+        let token = generate_token(
+            "alice@example.com".to_string(),
+            "alice".to_string(),
+            3,
+            "moderator".to_string(),
+        );
+
+        let claims = verify_token(token).unwrap();
+        assert_eq!(claims.sub, "alice@example.com");
+        assert_eq!(claims.email, "alice");
+        assert_eq!(claims.token_version, 3);
+        assert!(claims.is_moderator());
+        assert!(claims.iat.is_some() && claims.nbf.is_some());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let iat = now() - 2 * 60 * 60;
+        let token = sign(json!({
+            "sub": "alice",
+            "email": "alice@example.com",
+            "exp": now() - 60 * 60,
+            "iat": iat,
+            "nbf": iat,
+        }));
+
+        assert!(matches!(verify_token(token), Err(TokenError::Expired)));
+    }
+
+    #[test]
+    fn token_from_the_future_is_rejected() {
+        let iat = now() + 60 * 60;
+        let token = sign(json!({
+            "sub": "alice",
+            "email": "alice@example.com",
+            "exp": iat + 60 * 60,
+            "iat": iat,
+        }));
+
+        assert!(matches!(verify_token(token), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn legacy_token_is_accepted_for_one_lifetime() {
+        config::test();
+        let ttl = access_token_ttl().whole_seconds();
+        let legacy = |exp: i64| {
+            sign(json!({
+                "sub": "alice",
+                "email": "alice@example.com",
+                "exp": exp,
+            }))
+        };
+
+        assert!(verify_token(legacy(now() + ttl / 2)).is_ok());
+        assert!(matches!(
+            verify_token(legacy(now() + ttl + 60 * 60)),
+            Err(TokenError::Invalid)
+        ));
+    }
+}
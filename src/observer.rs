@@ -0,0 +1,46 @@
+// libs
+use crate::config;
+use std::sync::Arc;
+
+// structs
+// told about every websocket opening and closing, so embedders can hang
+// their own metrics or notifications off the connection lifecycle without
+// touching ws_handler. set AppState::observer to install one. both hooks run
+// on the connection's task, so anything slow belongs in a spawned task
+pub trait ConnectionObserver: Send + Sync {
+    fn on_connect(&self, username: &str);
+
+    // runs when the socket's loop has ended and presence has been updated
+    fn on_disconnect(&self, username: &str);
+}
+
+// the default: does nothing
+pub struct NoopObserver;
+
+// writes a log line per connect and disconnect; LOG_CONNECTIONS turns it on
+pub struct LoggingObserver;
+
+// mods
+impl ConnectionObserver for NoopObserver {
+    fn on_connect(&self, _username: &str) {}
+
+    fn on_disconnect(&self, _username: &str) {}
+}
+
+impl ConnectionObserver for LoggingObserver {
+    fn on_connect(&self, username: &str) {
+        tracing::info!(username, "websocket connected");
+    }
+
+    fn on_disconnect(&self, username: &str) {
+        tracing::info!(username, "websocket disconnected");
+    }
+}
+
+pub fn from_config() -> Arc<dyn ConnectionObserver> {
+    if config::get().log_connections {
+        Arc::new(LoggingObserver)
+    } else {
+        Arc::new(NoopObserver)
+    }
+}
@@ -0,0 +1,32 @@
+// libs
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::time::interval;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// mods
+// hard-deletes rather than soft-deletes, since the point is for old messages
+// to be gone; reactions and mentions go with them through their cascades
+async fn purge_expired_messages(pool: &PgPool, days: u32) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM messages WHERE time < NOW() - make_interval(days => $1)")
+        .bind(days as i32)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// runs once at startup and then hourly on the runtime, off the request path
+pub fn spawn(pool: PgPool, days: u32) {
+    tracing::info!(days, "message retention enabled");
+    actix_rt::spawn(async move {
+        let mut ticker = interval(PURGE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match purge_expired_messages(&pool, days).await {
+                Ok(removed) => tracing::info!(removed, days, "purged expired messages"),
+                Err(e) => tracing::error!(error = %e, "failed to purge expired messages"),
+            }
+        }
+    });
+}
@@ -0,0 +1,264 @@
+// libs
+use crate::audit::{self, Action};
+use crate::middlewares::{Claims, authenticate, client_ip, generate_random_token, hash_token};
+use crate::routes::chat::{AppState, ChatError};
+use crate::utc;
+use actix_web::{HttpRequest, HttpResponse, get, post, put, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use std::sync::Arc;
+
+// structs
+#[derive(Debug, Serialize, FromRow)]
+pub struct AdminUser {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub verified: bool,
+    pub role: String,
+    #[serde(serialize_with = "utc::option::serialize")]
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    pub limit: Option<i64>,
+    pub after: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub until: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MotdRequest {
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: String,
+    #[serde(serialize_with = "utc::serialize")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub before: Option<i64>,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+// mods
+async fn require_moderator(req: &HttpRequest, state: &AppState) -> Result<Claims, ChatError> {
+    let claims = authenticate(req, &state.db_pool).await?;
+    if !claims.is_moderator() {
+        return Err(ChatError::Forbidden("not_moderator"));
+    }
+    Ok(claims)
+}
+
+async fn require_admin(req: &HttpRequest, state: &AppState) -> Result<Claims, ChatError> {
+    let claims = authenticate(req, &state.db_pool).await?;
+    if claims.role != "admin" {
+        return Err(ChatError::Forbidden("not_admin"));
+    }
+    Ok(claims)
+}
+
+// routes
+#[get("/admin/users")]
+pub async fn list_users(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<UsersQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = require_moderator(&req, &state).await?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // fetch one extra row to know whether another page exists
+    let mut users = sqlx::query_as::<_, AdminUser>(
+        "SELECT id, username, email, verified, role, banned_until FROM users
+        WHERE id > $1
+        ORDER BY id
+        LIMIT $2",
+    )
+    .bind(query.after.unwrap_or(0))
+    .bind(limit + 1)
+    .fetch_all(&state.db_pool)
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, "failed to list users"))?;
+
+    let has_more = users.len() as i64 > limit;
+    users.truncate(limit as usize);
+    let next_cursor = if has_more {
+        users.last().map(|u| u.id)
+    } else {
+        None
+    };
+
+    tracing::info!(moderator = %claims.email, after = query.after, "moderator listed users");
+    Ok(HttpResponse::Ok().json(json!({
+        "users": users,
+        "next_cursor": next_cursor,
+    })))
+}
+
+// staff can't be banned here, so a moderator can't lock out the admins
+#[post("/admin/users/{username}/ban")]
+pub async fn ban_user(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+    ban: web::Json<BanRequest>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = require_moderator(&req, &state).await?;
+    let username = path.into_inner();
+
+    let target = sqlx::query_scalar::<_, String>("SELECT role FROM users WHERE username = $1")
+        .bind(&username)
+        .fetch_optional(&state.db_pool)
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "failed to look up user to ban"))?
+        .ok_or(ChatError::NotFound("user_not_found"))?;
+    if matches!(target.as_str(), "admin" | "moderator") {
+        return Err(ChatError::Forbidden("cannot_ban_staff"));
+    }
+
+    sqlx::query("UPDATE users SET banned_until = $1 WHERE username = $2")
+        .bind(ban.until)
+        .bind(&username)
+        .execute(&state.db_pool)
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "failed to ban user"))?;
+
+    tracing::info!(
+        moderator = %claims.email,
+        target = %username,
+        until = %utc::format(&ban.until),
+        "moderator banned user"
+    );
+    audit::record(
+        &state.db_pool,
+        Some(&claims.sub),
+        Action::UserBan,
+        Some(&username),
+        &client_ip(&req),
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(json!({
+        "username": username,
+        "banned_until": utc::format(&ban.until),
+    })))
+}
+
+// newest first. `from` and `to` bound `created_at`, both inclusive
+#[get("/admin/audit")]
+pub async fn audit_log(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, ChatError> {
+    require_admin(&req, &state).await?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // fetch one extra row to know whether another page exists
+    let mut entries = sqlx::query_as::<_, AuditEntry>(
+        "SELECT id, actor, action, target, ip, created_at FROM audit_log
+        WHERE ($1::TEXT IS NULL OR actor = $1)
+        AND ($2::TEXT IS NULL OR action = $2)
+        AND ($3::TIMESTAMPTZ IS NULL OR created_at >= $3)
+        AND ($4::TIMESTAMPTZ IS NULL OR created_at <= $4)
+        AND ($5::BIGINT IS NULL OR id < $5)
+        ORDER BY id DESC
+        LIMIT $6",
+    )
+    .bind(query.actor.as_deref())
+    .bind(query.action.as_deref())
+    .bind(query.from)
+    .bind(query.to)
+    .bind(query.before)
+    .bind(limit + 1)
+    .fetch_all(&state.db_pool)
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, "failed to read audit log"))?;
+
+    let has_more = entries.len() as i64 > limit;
+    entries.truncate(limit as usize);
+    let next_cursor = if has_more {
+        entries.last().map(|e| e.id)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "entries": entries,
+        "next_cursor": next_cursor,
+    })))
+}
+
+// replaces the message of the day until the next restart, which goes back to
+// MOTD / MOTD_PATH. null or blank text turns it off
+#[put("/admin/motd")]
+pub async fn set_motd(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    motd: web::Json<MotdRequest>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = require_admin(&req, &state).await?;
+    let text = motd
+        .into_inner()
+        .text
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty());
+
+    state.set_motd(text.clone());
+    tracing::info!(admin = %claims.email, set = text.is_some(), "admin changed the motd");
+    Ok(HttpResponse::Ok().json(json!({ "motd": text })))
+}
+
+// only the hash is stored, so the code is shown this once
+#[post("/admin/invites")]
+pub async fn create_invite(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = require_admin(&req, &state).await?;
+    let code = generate_random_token();
+
+    sqlx::query("INSERT INTO invite_codes (code_hash, created_by) VALUES ($1, $2)")
+        .bind(hash_token(&code))
+        .bind(&claims.sub)
+        .execute(&state.db_pool)
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "failed to create invite code"))?;
+
+    audit::record(
+        &state.db_pool,
+        Some(&claims.sub),
+        Action::InviteCreate,
+        None,
+        &client_ip(&req),
+    )
+    .await;
+    Ok(HttpResponse::Created().json(json!({ "code": code })))
+}
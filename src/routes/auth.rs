@@ -1,12 +1,22 @@
 // libs
 use crate::RegexValidator;
-use crate::middlewares::{generate_token, verify_token};
+use crate::audit::{self, Action};
+use crate::config::{self, Registration};
+use crate::db;
+use crate::middlewares::{
+    CSRF_COOKIE, RateLimiters, TokenError, access_token_ttl, authenticate, client_ip,
+    constant_time_eq, generate_random_token, generate_token, hash_token, verify_session,
+};
+use crate::routes::chat::ChatError;
+use crate::routes::metrics::METRICS;
+use crate::utc;
 use actix_web::{
     HttpRequest, HttpResponse, Responder,
-    cookie::{self, Cookie, SameSite},
-    delete, get, post, web,
+    cookie::{Cookie, SameSite},
+    delete, get, post, put, web,
 };
 use bcrypt::{DEFAULT_COST, hash, verify};
+use chrono::{DateTime, Utc};
 use lettre::message::Mailbox;
 use lettre::message::header::ContentType;
 use lettre::transport::smtp::authentication::Credentials;
@@ -15,24 +25,63 @@ use rand::random_range;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{FromRow, PgPool};
-use std::env;
+use std::sync::LazyLock;
 use time::Duration;
 
 // cookies config
 fn create_cookie(token: String) -> Cookie<'static> {
     Cookie::build("token", token)
         .path("/")
-        .secure(true)
-        .same_site(SameSite::Lax)
+        .secure(config::get().secure_cookies)
+        .same_site(SameSite::Strict)
+        .http_only(true)
+        .max_age(access_token_ttl())
+        .finish()
+}
+
+fn create_refresh_cookie(token: String) -> Cookie<'static> {
+    Cookie::build("refresh_token", token)
+        .path("/")
+        .secure(config::get().secure_cookies)
+        .same_site(SameSite::Strict)
         .http_only(true)
-        .max_age(Duration::days(1))
+        .max_age(Duration::days(REFRESH_TOKEN_DAYS))
+        .finish()
+}
+
+// readable by scripts on purpose: the client echoes it in X-CSRF-Token
+fn create_csrf_cookie() -> Cookie<'static> {
+    Cookie::build(CSRF_COOKIE, generate_random_token())
+        .path("/")
+        .secure(config::get().secure_cookies)
+        .same_site(SameSite::Strict)
+        .http_only(false)
+        .max_age(Duration::days(REFRESH_TOKEN_DAYS))
         .finish()
 }
 
+fn expired_cookie(name: &'static str) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name, "");
+    cookie.set_path("/");
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_secure(config::get().secure_cookies);
+    cookie.set_http_only(true);
+    cookie.set_max_age(Duration::seconds(0));
+    cookie
+}
+
 fn verify_cookie(req: HttpRequest) -> Option<String> {
     req.cookie("token").map(|c| c.value().to_string())
 }
 
+const REFRESH_TOKEN_DAYS: i64 = 30;
+const PASSWORD_RESET_MINUTES: i64 = 30;
+
+// anonymized messages are reassigned to this user; the brackets keep the
+// username out of reach of registration
+const DELETED_USER_EMAIL: &str = "deleted@kutter.invalid";
+const DELETED_USERNAME: &str = "[deleted]";
+
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 
 pub fn generate_verification_code() -> String {
@@ -45,9 +94,12 @@ pub fn generate_verification_code() -> String {
 }
 
 // email sender
-pub fn send_email(email: String, username: String, code: String) -> Result<(), String> {
-    let from_address = env::var("SMTP_USER")
-        .map_err(|e| format!("Failed to load SMTP_USER: {}", e))?
+fn send_mail(email: String, username: String, subject: &str, body: String) -> Result<(), String> {
+    let config = config::get();
+    let (Some(smtp_user), Some(smtp_password)) = (&config.smtp_user, &config.smtp_password) else {
+        return Err("SMTP_USER and SMTP_PSSWRD must be set to send email".to_string());
+    };
+    let from_address = smtp_user
         .parse()
         .map_err(|e| format!("Invalid sender email format: {}", e))?;
 
@@ -58,18 +110,12 @@ pub fn send_email(email: String, username: String, code: String) -> Result<(), S
     let email_message = Message::builder()
         .from(Mailbox::new(Some("Kutter".to_owned()), from_address))
         .to(Mailbox::new(Some(username.clone()), to_address))
-        .subject("Verify your account!")
+        .subject(subject)
         .header(ContentType::TEXT_PLAIN)
-        .body(format!(
-            "Hey {}, here's your verification code: {}\n\nCopy and paste this in the app to verify your account :3",
-            username, code
-        ))
+        .body(body)
         .map_err(|e| format!("Failed to build email: {}", e))?;
 
-    let creds = Credentials::new(
-        env::var("SMTP_USER").map_err(|e| format!("Failed to load SMTP_USER: {}", e))?,
-        env::var("SMTP_PSSWRD").map_err(|e| format!("Failed to load SMTP_PSSWRD: {}", e))?,
-    );
+    let creds = Credentials::new(smtp_user.clone(), smtp_password.clone());
 
     let mailer = SmtpTransport::relay("smtp.gmail.com")
         .map_err(|e| format!("Failed to create mailer: {}", e))?
@@ -83,6 +129,43 @@ pub fn send_email(email: String, username: String, code: String) -> Result<(), S
     Ok(())
 }
 
+pub fn send_email(email: String, username: String, code: String) -> Result<(), String> {
+    let body = format!(
+        "Hey {}, here's your verification code: {}\n\nCopy and paste this in the app to verify your account :3",
+        username, code
+    );
+    send_mail(email, username, "Verify your account!", body)
+}
+
+pub fn send_password_reset_email(
+    email: String,
+    username: String,
+    token: String,
+) -> Result<(), String> {
+    let app_url = &config::get().app_url;
+    let body = format!(
+        "Hey {}, someone asked to reset your password. If it was you, open this link within {} minutes:\n\n{}/reset-password?token={}\n\nIf it wasn't you, you can ignore this email.",
+        username,
+        PASSWORD_RESET_MINUTES,
+        app_url.trim_end_matches('/'),
+        token
+    );
+    send_mail(email, username, "Reset your password", body)
+}
+
+// passwords are bcrypt hashes (salted, cost DEFAULT_COST) checked with
+// bcrypt::verify, which compares in constant time. this one stands in for
+// unknown emails at login
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash(generate_random_token(), DEFAULT_COST).unwrap());
+
+// emails are matched case-insensitively: new accounts store them lowercase and
+// lookups compare against LOWER(email), which the users_email_lower_key index
+// covers and keeps unique
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
 // structs
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 struct User {
@@ -91,6 +174,18 @@ struct User {
     password: String,
     verified: bool,
     verification_code: Option<String>,
+    token_version: i32,
+    role: String,
+    banned_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshToken {
+    id: i32,
+    email: String,
+    family: String,
+    revoked: bool,
+    expires_at: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -98,6 +193,7 @@ struct RegisterForm {
     username: String,
     email: String,
     password: String,
+    invite_code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -112,15 +208,113 @@ struct VerificationData {
     code: String,
 }
 
+#[derive(Deserialize)]
+struct UsernameQuery {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ResendVerificationForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountForm {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct ChangeUsernameForm {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordForm {
+    current_password: String,
+    new_password: String,
+    // defaults to true
+    sign_out_other_sessions: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    token: String,
+    password: String,
+}
+
+// refresh tokens are rotated on every use; all tokens minted from the same
+// login share a family so a replayed token can revoke the whole chain
+async fn issue_refresh_token(
+    pool: &PgPool,
+    email: &str,
+    family: Option<String>,
+) -> Result<String, sqlx::Error> {
+    let token = generate_random_token();
+    let family = family.unwrap_or_else(generate_random_token);
+
+    sqlx::query(
+        "INSERT INTO refresh_tokens (email, token_hash, family, expires_at)
+        VALUES ($1, $2, $3, $4)",
+    )
+    .bind(email)
+    .bind(hash_token(&token))
+    .bind(family)
+    .bind(Utc::now() + chrono::Duration::days(REFRESH_TOKEN_DAYS))
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+async fn revoke_refresh_family(pool: &PgPool, family: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE family = $1")
+        .bind(family)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 // routes
+// answers 429 once the client ip has used up its /register + /login budget
+fn auth_rate_limited(req: &HttpRequest, limiters: &RateLimiters) -> Option<HttpResponse> {
+    let retry_after = limiters.auth.check(&client_ip(req)).err()?.as_secs().max(1);
+    Some(
+        HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(json!({
+                "error": "rate_limited",
+                "retry_after": retry_after,
+            })),
+    )
+}
+
 #[post("/register")]
 pub async fn register(
+    http_req: HttpRequest,
     pool: web::Data<PgPool>,
     req: web::Json<RegisterForm>,
     validator: web::Data<RegexValidator>,
+    limiters: web::Data<RateLimiters>,
 ) -> impl Responder {
+    if let Some(response) = auth_rate_limited(&http_req, &limiters) {
+        return response;
+    }
+
+    let registration = config::get().registration;
+    if registration == Registration::Closed {
+        return HttpResponse::Forbidden().json(json!({ "error": "registration_closed" }));
+    }
+    if registration == Registration::InviteOnly && req.invite_code.is_none() {
+        return HttpResponse::Forbidden().json(json!({ "error": "invite_required" }));
+    }
+
     let username = req.username.clone();
-    let email = req.email.clone();
+    let email = normalize_email(&req.email);
     let password = req.password.clone();
 
     if username.is_empty() || email.is_empty() || password.is_empty() {
@@ -144,10 +338,11 @@ pub async fn register(
         }));
     }
 
-    if !validator.validate_password(&password) {
+    if let Err(errors) = validator.validate_password_detailed(&password) {
         return HttpResponse::BadRequest().json(json!({
             "status": "error",
-            "message": "password must be at least 6 characters long, contain at least one uppercase letter, one number, and one special character",
+            "message": validator.password_policy.describe(),
+            "errors": errors,
         }));
     }
 
@@ -163,27 +358,43 @@ pub async fn register(
 
     let code = generate_verification_code();
 
-    let email_exists = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
-        .bind(&email)
-        .fetch_optional(pool.get_ref())
-        .await
-    {
-        Ok(user) => user.is_some(),
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
         Err(_) => {
             return HttpResponse::InternalServerError().json(json!({
                 "status": "error",
-                "message": "failed to check if email exists",
+                "message": "failed to create user",
             }));
         }
     };
 
-    if email_exists {
-        return HttpResponse::Conflict().json(json!({
-            "status": "error",
-            "message": "email already exists",
-        }));
+    // claimed in the same transaction as the insert, so a signup that fails
+    // (say, on a taken username) leaves the code usable
+    if registration == Registration::InviteOnly
+        && let Some(invite_code) = req.invite_code.as_deref()
+    {
+        let claimed = sqlx::query(
+            "UPDATE invite_codes SET used_by = $1, used_at = CURRENT_TIMESTAMP
+            WHERE code_hash = $2 AND used_at IS NULL",
+        )
+        .bind(&email)
+        .bind(hash_token(invite_code))
+        .execute(&mut *tx)
+        .await;
+        match claimed {
+            Ok(result) if result.rows_affected() == 1 => {}
+            Ok(_) => return HttpResponse::Forbidden().json(json!({ "error": "invalid_invite" })),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to claim invite code");
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to create user",
+                }));
+            }
+        }
     }
 
+    // the unique constraints decide, so two concurrent signups can't both win
     let insert_result = sqlx::query_as::<_, User>(
         "INSERT INTO users (username, email, password, verification_code) VALUES ($1, $2, $3, $4) RETURNING *",
     )
@@ -191,11 +402,18 @@ pub async fn register(
     .bind(&email)
     .bind(password_hash)
     .bind(&code)
-    .fetch_one(pool.get_ref())
+    .fetch_one(&mut *tx)
     .await;
 
     match insert_result {
         Ok(user) => {
+            if let Err(e) = tx.commit().await {
+                tracing::error!(error = %e, "failed to create user");
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to create user",
+                }));
+            }
             if let Err(e) = send_email(email, username, code) {
                 return HttpResponse::InternalServerError().json(json!({
                     "status": "error",
@@ -208,19 +426,95 @@ pub async fn register(
                 "user": user.username
             }))
         }
+        Err(e) => match db::unique_violation(&e) {
+            Some("users_username_key" | "users_username_lower_key") => {
+                HttpResponse::Conflict().json(json!({ "error": "username_taken" }))
+            }
+            Some("users_email_key" | "users_email_lower_key") => {
+                HttpResponse::Conflict().json(json!({ "error": "email_taken" }))
+            }
+            _ => {
+                tracing::error!(error = %e, "failed to create user");
+                HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to create user",
+                }))
+            }
+        },
+    }
+}
+
+#[get("/auth/password-policy")]
+pub async fn password_policy(validator: web::Data<RegexValidator>) -> impl Responder {
+    HttpResponse::Ok().json(validator.password_policy)
+}
+
+#[get("/auth/username-available")]
+pub async fn username_available(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    query: web::Query<UsernameQuery>,
+    validator: web::Data<RegexValidator>,
+    limiters: web::Data<RateLimiters>,
+) -> impl Responder {
+    if let Err(retry_after) = limiters.username_check.check(&client_ip(&req)) {
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(json!({
+                "status": "error",
+                "message": "too many requests",
+            }));
+    }
+
+    if !validator.username.is_match(&query.username) {
+        return HttpResponse::Ok().json(json!({
+            "available": false,
+            "valid_format": false,
+        }));
+    }
+
+    match sqlx::query("SELECT 1 FROM users WHERE username = $1")
+        .bind(&query.username)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(row) => HttpResponse::Ok().json(json!({
+            "available": row.is_none(),
+            "valid_format": true,
+        })),
         Err(_) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
-            "message": "failed to create user",
+            "message": "failed to check username",
         })),
     }
 }
 
 #[post("/login")]
-pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl Responder {
-    let email = req.email.clone();
+pub async fn login(
+    http_req: HttpRequest,
+    pool: web::Data<PgPool>,
+    req: web::Json<LoginForm>,
+    limiters: web::Data<RateLimiters>,
+) -> impl Responder {
+    if let Some(response) = auth_rate_limited(&http_req, &limiters) {
+        return response;
+    }
+
+    let email = normalize_email(&req.email);
     let password = req.password.clone();
+    let lockout_key = email.clone();
 
-    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+    if let Err(retry_after) = limiters.login.check(&lockout_key) {
+        let retry_after = retry_after.as_secs().max(1);
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(json!({
+                "error": "account_locked",
+                "retry_after": retry_after,
+            }));
+    }
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = $1")
         .bind(&email)
         .fetch_optional(pool.get_ref())
         .await
@@ -237,9 +531,22 @@ pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl R
     let user = match user {
         Some(user) => user,
         None => {
+            // spend the same bcrypt time as a real check so response times
+            // don't reveal which emails are registered
+            let _ = verify(&password, &DUMMY_PASSWORD_HASH);
+            limiters.login.record_failure(&lockout_key);
+            METRICS.auth_failures_total.inc();
+            audit::record(
+                pool.get_ref(),
+                Some(&email),
+                Action::LoginFailure,
+                None,
+                &client_ip(&http_req),
+            )
+            .await;
             return HttpResponse::Unauthorized().json(json!({
                 "status": "error",
-                "message": "user not found",
+                "message": "invalid email or password",
             }));
         }
     };
@@ -256,22 +563,73 @@ pub async fn login(pool: web::Data<PgPool>, req: web::Json<LoginForm>) -> impl R
 
     match password_valid {
         true => {
-            let token = generate_token(user.email.clone(), user.username.clone());
-            let cookie = create_cookie(token);
-            HttpResponse::Ok().cookie(cookie).json(json!({
-                "status": "success",
-                "message": "user logged in",
-                "user": {
-                    "username": user.username,
-                    "email": user.email
+            limiters.login.reset(&lockout_key);
+            if let Some(banned_until) = user.banned_until.filter(|until| *until > Utc::now()) {
+                audit::record(
+                    pool.get_ref(),
+                    Some(&user.email),
+                    Action::LoginFailure,
+                    None,
+                    &client_ip(&http_req),
+                )
+                .await;
+                return HttpResponse::Forbidden().json(json!({
+                    "error": "banned",
+                    "banned_until": utc::format(&banned_until),
+                }));
+            }
+            let refresh_token = match issue_refresh_token(pool.get_ref(), &user.email, None).await {
+                Ok(token) => token,
+                Err(_) => {
+                    return HttpResponse::InternalServerError().json(json!({
+                        "status": "error",
+                        "message": "failed to create session",
+                    }));
                 }
-            }))
+            };
+            let token = generate_token(
+                user.email.clone(),
+                user.username.clone(),
+                user.token_version,
+                user.role.clone(),
+            );
+            let cookie = create_cookie(token);
+            audit::record(
+                pool.get_ref(),
+                Some(&user.email),
+                Action::LoginSuccess,
+                None,
+                &client_ip(&http_req),
+            )
+            .await;
+            HttpResponse::Ok()
+                .cookie(cookie)
+                .cookie(create_refresh_cookie(refresh_token))
+                .cookie(create_csrf_cookie())
+                .json(json!({
+                    "status": "success",
+                    "message": "user logged in",
+                    "user": {
+                        "username": user.username,
+                        "email": user.email
+                    }
+                }))
         }
         false => {
-            return HttpResponse::Unauthorized().json(json!({
+            limiters.login.record_failure(&lockout_key);
+            METRICS.auth_failures_total.inc();
+            audit::record(
+                pool.get_ref(),
+                Some(&user.email),
+                Action::LoginFailure,
+                None,
+                &client_ip(&http_req),
+            )
+            .await;
+            HttpResponse::Unauthorized().json(json!({
                 "status": "error",
-                "message": "invalid password",
-            }));
+                "message": "invalid email or password",
+            }))
         }
     }
 }
@@ -288,7 +646,7 @@ pub async fn verify_user(req: HttpRequest, pool: web::Data<PgPool>) -> impl Resp
         }
     };
 
-    let claims = match verify_token(token) {
+    let claims = match verify_session(pool.get_ref(), token).await {
         Ok(claims) => claims,
         Err(_) => {
             return HttpResponse::Ok().json(json!({
@@ -318,15 +676,39 @@ pub async fn verify_user(req: HttpRequest, pool: web::Data<PgPool>) -> impl Resp
     }
 }
 
+// unlike /verify, a missing or bad session is a 401 with the usual error body
+#[get("/me")]
+pub async fn me(req: HttpRequest, pool: web::Data<PgPool>) -> Result<HttpResponse, ChatError> {
+    let claims = authenticate(&req, pool.get_ref()).await?;
+
+    let (email, username, role, created_at, last_seen) =
+        sqlx::query_as::<_, (String, String, String, DateTime<Utc>, Option<DateTime<Utc>>)>(
+            "SELECT email, username, role, created_at, last_seen FROM users WHERE email = $1",
+        )
+        .bind(&claims.sub)
+        .fetch_optional(pool.get_ref())
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "failed to fetch profile"))?
+        .ok_or(TokenError::Invalid)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "email": email,
+        "username": username,
+        "role": role,
+        "created_at": utc::format(&created_at),
+        "last_seen": last_seen.as_ref().map(utc::format),
+    })))
+}
+
 #[post("/verify_email")]
 pub async fn verify_email(
     pool: web::Data<PgPool>,
     req: web::Json<VerificationData>,
 ) -> impl Responder {
-    let email = req.email.clone();
+    let email = normalize_email(&req.email);
     let code = req.code.clone();
 
-    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = $1")
         .bind(&email)
         .fetch_optional(pool.get_ref())
         .await
@@ -347,7 +729,11 @@ pub async fn verify_email(
         }));
     }
 
-    if user.verification_code.as_deref() != Some(code.as_str()) {
+    let code_matches = user
+        .verification_code
+        .as_deref()
+        .is_some_and(|expected| constant_time_eq(expected.as_bytes(), code.as_bytes()));
+    if !code_matches {
         return HttpResponse::Unauthorized().json(json!({
             "status": "error",
             "message": "invalid verification code"
@@ -355,18 +741,36 @@ pub async fn verify_email(
     }
 
     match sqlx::query("UPDATE users SET verified = true WHERE email = $1")
-        .bind(&email)
+        .bind(&user.email)
         .execute(pool.get_ref())
         .await
     {
         Ok(_) => {
-            let token = generate_token(user.email.clone(), user.username.clone());
+            let refresh_token = match issue_refresh_token(pool.get_ref(), &user.email, None).await {
+                Ok(token) => token,
+                Err(_) => {
+                    return HttpResponse::InternalServerError().json(json!({
+                        "status": "error",
+                        "message": "failed to create session",
+                    }));
+                }
+            };
+            let token = generate_token(
+                user.email.clone(),
+                user.username.clone(),
+                user.token_version,
+                user.role.clone(),
+            );
             let cookie = create_cookie(token);
 
-            HttpResponse::Ok().cookie(cookie).json(json!({
-                "status": "success",
-                "message": "user verified successfully"
-            }))
+            HttpResponse::Ok()
+                .cookie(cookie)
+                .cookie(create_refresh_cookie(refresh_token))
+                .cookie(create_csrf_cookie())
+                .json(json!({
+                    "status": "success",
+                    "message": "user verified successfully"
+                }))
         }
         Err(_) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
@@ -375,15 +779,800 @@ pub async fn verify_email(
     }
 }
 
-#[delete("/logout")]
-pub async fn logout() -> impl Responder {
-    let mut cookie = Cookie::new("token", "");
-    cookie.set_same_site(cookie::SameSite::Lax);
-    cookie.set_secure(true);
-    cookie.set_http_only(true);
-    cookie.set_max_age(Duration::seconds(0));
-    HttpResponse::Ok().cookie(cookie).json(json!({
+// like forgot_password, the answer is the same whether or not the account
+// exists or is already verified
+#[post("/auth/resend-verification")]
+pub async fn resend_verification(
+    pool: web::Data<PgPool>,
+    req: web::Json<ResendVerificationForm>,
+    limiters: web::Data<RateLimiters>,
+) -> impl Responder {
+    if let Err(retry_after) = limiters
+        .resend_verification
+        .check(&normalize_email(&req.email))
+    {
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(json!({
+                "status": "error",
+                "message": "too many requests",
+            }));
+    }
+
+    let response = HttpResponse::Ok().json(json!({
         "status": "success",
-        "message": "user logged out",
-    }))
+        "message": "if an unverified account exists for this email, a new code has been sent",
+    }));
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = $1")
+        .bind(normalize_email(&req.email))
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(user)) if !user.verified => user,
+        Ok(_) => return response,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to fetch user for verification resend");
+            return response;
+        }
+    };
+
+    let code = generate_verification_code();
+    if let Err(e) = sqlx::query("UPDATE users SET verification_code = $1 WHERE email = $2")
+        .bind(&code)
+        .bind(&user.email)
+        .execute(pool.get_ref())
+        .await
+    {
+        tracing::error!(error = %e, "failed to store verification code");
+        return response;
+    }
+
+    if let Err(e) = send_email(user.email, user.username, code) {
+        tracing::error!(error = %e, "failed to send verification email");
+    }
+
+    response
+}
+
+#[post("/refresh")]
+pub async fn refresh(req: HttpRequest, pool: web::Data<PgPool>) -> impl Responder {
+    let presented = match req.cookie("refresh_token") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "missing refresh token",
+            }));
+        }
+    };
+
+    let stored = match sqlx::query_as::<_, RefreshToken>(
+        "SELECT id, email, family, revoked, expires_at FROM refresh_tokens WHERE token_hash = $1",
+    )
+    .bind(hash_token(&presented))
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid refresh token",
+            }));
+        }
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to get refresh token",
+            }));
+        }
+    };
+
+    if stored.expires_at <= Utc::now() {
+        return HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": "refresh token expired",
+        }));
+    }
+
+    // the conditional update makes two concurrent uses of the same token
+    // count as reuse instead of both succeeding
+    let rotated = match sqlx::query(
+        "UPDATE refresh_tokens SET revoked = TRUE WHERE id = $1 AND revoked = FALSE",
+    )
+    .bind(stored.id)
+    .execute(pool.get_ref())
+    .await
+    {
+        Ok(result) => !stored.revoked && result.rows_affected() == 1,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to rotate refresh token",
+            }));
+        }
+    };
+
+    if !rotated {
+        if let Err(e) = revoke_refresh_family(pool.get_ref(), &stored.family).await {
+            tracing::error!(error = %e, "failed to revoke refresh token family");
+        }
+        return HttpResponse::Unauthorized()
+            .cookie(expired_cookie("token"))
+            .cookie(expired_cookie("refresh_token"))
+            .cookie(expired_cookie(CSRF_COOKIE))
+            .json(json!({
+                "status": "error",
+                "message": "refresh token reuse detected",
+            }));
+    }
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(&stored.email)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(user)) => user,
+        _ => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "user not found",
+            }));
+        }
+    };
+
+    let refresh_token =
+        match issue_refresh_token(pool.get_ref(), &user.email, Some(stored.family)).await {
+            Ok(token) => token,
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to rotate refresh token",
+                }));
+            }
+        };
+    let token = generate_token(
+        user.email.clone(),
+        user.username.clone(),
+        user.token_version,
+        user.role.clone(),
+    );
+
+    HttpResponse::Ok()
+        .cookie(create_cookie(token))
+        .cookie(create_refresh_cookie(refresh_token))
+        .cookie(create_csrf_cookie())
+        .json(json!({
+            "status": "success",
+            "message": "token refreshed",
+        }))
+}
+
+#[post("/logout_all")]
+pub async fn logout_all(req: HttpRequest, pool: web::Data<PgPool>) -> impl Responder {
+    let ip = client_ip(&req);
+    let token = match verify_cookie(req) {
+        Some(token) => token,
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_session(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let bumped = sqlx::query("UPDATE users SET token_version = token_version + 1 WHERE email = $1")
+        .bind(&claims.sub)
+        .execute(pool.get_ref())
+        .await;
+    if bumped.is_err() {
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": "failed to revoke sessions",
+        }));
+    }
+
+    // refresh tokens would otherwise mint fresh access tokens at the new version
+    if let Err(e) = sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE email = $1")
+        .bind(&claims.sub)
+        .execute(pool.get_ref())
+        .await
+    {
+        tracing::error!(error = %e, "failed to revoke refresh tokens");
+    }
+    audit::record(
+        pool.get_ref(),
+        Some(&claims.sub),
+        Action::LogoutAll,
+        None,
+        &ip,
+    )
+    .await;
+
+    HttpResponse::Ok()
+        .cookie(expired_cookie("token"))
+        .cookie(expired_cookie("refresh_token"))
+        .cookie(expired_cookie(CSRF_COOKIE))
+        .json(json!({
+            "status": "success",
+            "message": "all sessions logged out",
+        }))
+}
+
+// always answers 200 so the response can't be used to probe which emails exist
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    pool: web::Data<PgPool>,
+    req: web::Json<ForgotPasswordForm>,
+) -> impl Responder {
+    let response = HttpResponse::Ok().json(json!({
+        "status": "success",
+        "message": "if an account exists for this email, a reset link has been sent",
+    }));
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = $1")
+        .bind(normalize_email(&req.email))
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return response,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to fetch user for password reset");
+            return response;
+        }
+    };
+
+    let token = generate_random_token();
+    let inserted = sqlx::query(
+        "INSERT INTO password_resets (email, token_hash, expires_at) VALUES ($1, $2, $3)",
+    )
+    .bind(&user.email)
+    .bind(hash_token(&token))
+    .bind(Utc::now() + chrono::Duration::minutes(PASSWORD_RESET_MINUTES))
+    .execute(pool.get_ref())
+    .await;
+
+    if let Err(e) = inserted {
+        tracing::error!(error = %e, "failed to store password reset token");
+        return response;
+    }
+
+    if let Err(e) = send_password_reset_email(user.email, user.username, token) {
+        tracing::error!(error = %e, "failed to send password reset email");
+    }
+
+    response
+}
+
+#[post("/password/reset")]
+pub async fn reset_password(
+    http_req: HttpRequest,
+    pool: web::Data<PgPool>,
+    req: web::Json<ResetPasswordForm>,
+    validator: web::Data<RegexValidator>,
+) -> impl Responder {
+    if let Err(errors) = validator.validate_password_detailed(&req.password) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": validator.password_policy.describe(),
+            "errors": errors,
+        }));
+    }
+
+    let password_hash = match hash(&req.password, DEFAULT_COST) {
+        Ok(hash) => hash,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to hash password",
+            }));
+        }
+    };
+
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to reset password",
+            }));
+        }
+    };
+
+    // consuming the token in the same statement that checks it keeps it single-use
+    let email = match sqlx::query_scalar::<_, String>(
+        "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        RETURNING email",
+    )
+    .bind(hash_token(&req.token))
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(email)) => email,
+        Ok(None) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": "invalid or expired reset token",
+            }));
+        }
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to reset password",
+            }));
+        }
+    };
+
+    // a reset also signs out every existing session
+    let updated = sqlx::query(
+        "UPDATE users SET password = $1, token_version = token_version + 1 WHERE email = $2",
+    )
+    .bind(password_hash)
+    .bind(&email)
+    .execute(&mut *tx)
+    .await;
+    let revoked = sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE email = $1")
+        .bind(&email)
+        .execute(&mut *tx)
+        .await;
+
+    if updated.is_err() || revoked.is_err() || tx.commit().await.is_err() {
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": "failed to reset password",
+        }));
+    }
+
+    audit::record(
+        pool.get_ref(),
+        Some(&email),
+        Action::PasswordReset,
+        None,
+        &client_ip(&http_req),
+    )
+    .await;
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "message": "password reset",
+    }))
+}
+
+// the current password is required so an unattended session can't lock the
+// owner out. wrong guesses count towards the same lockout as logins. signing
+// out other sessions bumps token_version, so this one is handed fresh tokens
+#[post("/auth/change-password")]
+pub async fn change_password(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    form: web::Json<ChangePasswordForm>,
+    validator: web::Data<RegexValidator>,
+    limiters: web::Data<RateLimiters>,
+) -> impl Responder {
+    let ip = client_ip(&req);
+    let token = match verify_cookie(req) {
+        Some(token) => token,
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_session(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    if let Err(retry_after) = limiters.login.check(&claims.sub) {
+        let retry_after = retry_after.as_secs().max(1);
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(json!({
+                "error": "account_locked",
+                "retry_after": retry_after,
+            }));
+    }
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(&claims.sub)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "user not found",
+            }));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to fetch user for password change");
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to change password",
+            }));
+        }
+    };
+
+    if !verify(&form.current_password, &user.password).unwrap_or(false) {
+        limiters.login.record_failure(&claims.sub);
+        return HttpResponse::Forbidden().json(json!({ "error": "invalid_current_password" }));
+    }
+    limiters.login.reset(&claims.sub);
+
+    if let Err(errors) = validator.validate_password_detailed(&form.new_password) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "weak_password",
+            "message": validator.password_policy.describe(),
+            "errors": errors,
+        }));
+    }
+
+    let password_hash = match hash(&form.new_password, DEFAULT_COST) {
+        Ok(hash) => hash,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to hash password",
+            }));
+        }
+    };
+
+    let sign_out_others = form.sign_out_other_sessions.unwrap_or(true);
+    let changed = async {
+        let mut tx = pool.begin().await?;
+        let token_version = sqlx::query_scalar::<_, i32>(
+            "UPDATE users SET password = $1, token_version = token_version + $2
+            WHERE email = $3 RETURNING token_version",
+        )
+        .bind(password_hash)
+        .bind(sign_out_others as i32)
+        .bind(&user.email)
+        .fetch_one(&mut *tx)
+        .await?;
+        if sign_out_others {
+            sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE email = $1")
+                .bind(&user.email)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(token_version)
+    }
+    .await;
+
+    let token_version = match changed {
+        Ok(token_version) => token_version,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to change password");
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to change password",
+            }));
+        }
+    };
+    audit::record(
+        pool.get_ref(),
+        Some(&user.email),
+        Action::PasswordChange,
+        None,
+        &ip,
+    )
+    .await;
+
+    let mut response = HttpResponse::Ok();
+    if sign_out_others {
+        let refresh_token = match issue_refresh_token(pool.get_ref(), &user.email, None).await {
+            Ok(token) => token,
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to create session",
+                }));
+            }
+        };
+        let token = generate_token(
+            user.email.clone(),
+            user.username.clone(),
+            token_version,
+            user.role.clone(),
+        );
+        response
+            .cookie(create_cookie(token))
+            .cookie(create_refresh_cookie(refresh_token));
+    }
+    response.json(json!({
+        "status": "success",
+        "message": "password changed",
+    }))
+}
+
+// removes the user in one transaction; their messages are handed to the
+// [deleted] user when `sentinel_password` is given and deleted otherwise
+async fn delete_account_data(
+    pool: &PgPool,
+    email: &str,
+    sentinel_password: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(sentinel_password) = sentinel_password {
+        sqlx::query(
+            "INSERT INTO users (username, email, password) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(DELETED_USERNAME)
+        .bind(DELETED_USER_EMAIL)
+        .bind(sentinel_password)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE messages SET email = $1, username = $2 WHERE email = $3")
+            .bind(DELETED_USER_EMAIL)
+            .bind(DELETED_USERNAME)
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE direct_messages SET sender_email = $1 WHERE sender_email = $2")
+            .bind(DELETED_USER_EMAIL)
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE direct_messages SET recipient_email = $1 WHERE recipient_email = $2")
+            .bind(DELETED_USER_EMAIL)
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query("DELETE FROM messages WHERE email = $1")
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM direct_messages WHERE sender_email = $1 OR recipient_email = $1")
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // refresh tokens, password resets, reactions and read markers cascade
+    sqlx::query("DELETE FROM users WHERE email = $1")
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+#[delete("/account")]
+pub async fn delete_account(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    form: web::Json<DeleteAccountForm>,
+) -> impl Responder {
+    let ip = client_ip(&req);
+    let token = match verify_cookie(req) {
+        Some(token) => token,
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_session(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(&claims.sub)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "user not found",
+            }));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to fetch user for account deletion");
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "failed to delete account",
+            }));
+        }
+    };
+
+    if !verify(&form.password, &user.password).unwrap_or(false) {
+        return HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": "invalid password",
+        }));
+    }
+
+    // nobody knows the preimage, so the [deleted] user can never log in
+    let sentinel_password = if config::get().hard_delete_accounts {
+        None
+    } else {
+        match hash(generate_random_token(), DEFAULT_COST) {
+            Ok(hash) => Some(hash),
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to delete account",
+                }));
+            }
+        }
+    };
+
+    if let Err(e) =
+        delete_account_data(pool.get_ref(), &user.email, sentinel_password.as_deref()).await
+    {
+        tracing::error!(error = %e, "failed to delete account");
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": "failed to delete account",
+        }));
+    }
+    audit::record(
+        pool.get_ref(),
+        Some(&user.email),
+        Action::AccountDelete,
+        None,
+        &ip,
+    )
+    .await;
+
+    HttpResponse::Ok()
+        .cookie(expired_cookie("token"))
+        .cookie(expired_cookie("refresh_token"))
+        .cookie(expired_cookie(CSRF_COOKIE))
+        .json(json!({
+            "status": "success",
+            "message": "account deleted",
+        }))
+}
+
+// messages.username follows the rename through its ON UPDATE CASCADE foreign
+// key, so the single UPDATE moves the user and their history atomically
+#[put("/account/username")]
+pub async fn change_username(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    form: web::Json<ChangeUsernameForm>,
+    validator: web::Data<RegexValidator>,
+) -> impl Responder {
+    let token = match verify_cookie(req) {
+        Some(token) => token,
+        None => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "not authenticated"
+            }));
+        }
+    };
+
+    let claims = match verify_session(pool.get_ref(), token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "invalid token"
+            }));
+        }
+    };
+
+    if !validator.username.is_match(&form.username) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "username must be between 2 and 20 characters, lowercase alphabetic with _ or -",
+        }));
+    }
+
+    let renamed =
+        sqlx::query_as::<_, User>("UPDATE users SET username = $1 WHERE email = $2 RETURNING *")
+            .bind(&form.username)
+            .bind(&claims.sub)
+            .fetch_optional(pool.get_ref())
+            .await;
+
+    match renamed {
+        Ok(Some(user)) => {
+            // the access token carries the username, so hand out one with the new name
+            let token = generate_token(
+                user.email.clone(),
+                user.username.clone(),
+                user.token_version,
+                user.role.clone(),
+            );
+            HttpResponse::Ok().cookie(create_cookie(token)).json(json!({
+                "status": "success",
+                "message": "username changed",
+                "user": user.username,
+            }))
+        }
+        Ok(None) => HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": "user not found",
+        })),
+        Err(e) => match db::unique_violation(&e) {
+            Some("users_username_key" | "users_username_lower_key") => {
+                HttpResponse::Conflict().json(json!({ "error": "username_taken" }))
+            }
+            _ => {
+                tracing::error!(error = %e, "failed to change username");
+                HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "failed to change username",
+                }))
+            }
+        },
+    }
+}
+
+#[delete("/logout")]
+pub async fn logout(req: HttpRequest, pool: web::Data<PgPool>) -> impl Responder {
+    if let Some(cookie) = req.cookie("refresh_token") {
+        let revoked = sqlx::query_scalar::<_, String>(
+            "UPDATE refresh_tokens SET revoked = TRUE
+            WHERE family = (SELECT family FROM refresh_tokens WHERE token_hash = $1)
+            RETURNING email",
+        )
+        .bind(hash_token(cookie.value()))
+        .fetch_all(pool.get_ref())
+        .await;
+
+        match revoked {
+            // a cookie that matches no session isn't a logout worth recording
+            Ok(emails) => {
+                if let Some(actor) = emails.first() {
+                    audit::record(
+                        pool.get_ref(),
+                        Some(actor.as_str()),
+                        Action::Logout,
+                        None,
+                        &client_ip(&req),
+                    )
+                    .await;
+                }
+            }
+            Err(e) => tracing::error!(error = %e, "failed to revoke refresh token"),
+        }
+    }
+
+    HttpResponse::Ok()
+        .cookie(expired_cookie("token"))
+        .cookie(expired_cookie("refresh_token"))
+        .cookie(expired_cookie(CSRF_COOKIE))
+        .json(json!({
+            "status": "success",
+            "message": "user logged out",
+        }))
 }
@@ -1,13 +1,42 @@
 // libs
-use crate::middlewares::verify_token;
-use actix_web::{Error, HttpRequest, HttpResponse, Responder, get, web};
-use actix_ws::Message;
+use crate::RegexValidator;
+use crate::audit::{self, Action};
+use crate::config::{self, SanitizeMode};
+use crate::fanout;
+use crate::middlewares::{
+    RateLimiters, TokenError, WS_BEARER_PROTOCOL, authenticate, authenticate_ws, client_ip,
+    verify_session,
+};
+use crate::observer::{self, ConnectionObserver};
+use crate::routes::dm::{self, DirectMessage, SendDmRequest};
+use crate::routes::mentions;
+use crate::routes::metrics::{METRICS, time_query};
+use crate::routes::reactions::{self, ReactionRequest};
+use crate::routes::receipts::{self, MarkReadRequest};
+use crate::routes::uploads::{DiskStore, UploadStore};
+use crate::shortcodes;
+use crate::utc;
+use crate::wordfilter::WordFilter;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse, ResponseError, get, post, web};
+use actix_ws::{CloseCode, CloseReason, Item, Message, MessageStream, Session};
 use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
 use futures_util::StreamExt as _;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, interval};
 
 // structs
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -16,12 +45,32 @@ pub struct ChatMessage {
     pub email: String,
     pub username: String,
     pub message: String,
+    #[serde(serialize_with = "utc::serialize")]
     pub time: DateTime<Utc>,
+    #[serde(serialize_with = "utc::option::serialize")]
+    pub edited_at: Option<DateTime<Utc>>,
+    pub room_id: i32,
+    pub attachment_url: Option<String>,
+    pub parent_id: Option<i32>,
+    // the sender's idempotency key; never sent out with the message, only
+    // echoed back to the sender in its ack
+    #[serde(skip_serializing)]
+    pub nonce: Option<String>,
+    // emoji -> count; only filled by queries that select a `reactions` column,
+    // and left out when the message has none
+    #[sqlx(default, json(nullable))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<HashMap<String, i64>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewMessage {
     pub message: String,
+    pub attachment_url: Option<String>,
+    pub reply_to: Option<i32>,
+    pub nonce: Option<String>,
+    // echoed back to the sender only, in the ack or error frame for this send
+    pub client_msg_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,179 +79,2406 @@ pub struct WebSocketMessage {
     pub payload: serde_json::Value,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ResyncRequest {
+    pub since_id: i32,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeleteMessageRequest {
     pub id: i32,
 }
 
-#[derive(Debug, Clone, Serialize)]
-#[serde(tag = "action", rename_all = "snake_case")]
-pub enum OutgoingMessage {
-    NewMessage(ChatMessage),
-    Delete { message_id: i32 },
+#[derive(Debug, Deserialize)]
+pub struct EditMessageRequest {
+    pub id: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TypingRequest {
+    pub is_typing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReauthRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomQuery {
+    pub room: Option<String>,
+    // opt in to a zlib-compressed binary history frame
+    #[serde(default)]
+    pub compress: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    pub room: Option<String>,
+    pub limit: Option<i64>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    #[serde(default)]
+    pub include_reactions: bool,
+    pub order: Option<String>,
+    pub user: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OnlineQuery {
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub room: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesPage<T = ChatMessage, C = i32> {
+    pub messages: Vec<T>,
+    pub next_cursor: Option<C>,
+}
+
+// a position in the (time, id) order of /messages, sent as
+// "<unix micros>_<id>". the id alone can't place a message among others with
+// the same timestamp, so both travel. a bare id, as handed out before, is
+// still accepted and its time is looked up
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCursor {
+    pub time: Option<DateTime<Utc>>,
+    pub id: i32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum OutgoingMessage {
+    NewMessage(ChatMessage),
+    DirectMessage(DirectMessage),
+    Delete {
+        room_id: i32,
+        message_id: i32,
+    },
+    Edit {
+        room_id: i32,
+        message_id: i32,
+        message: String,
+        #[serde(serialize_with = "utc::serialize")]
+        edited_at: DateTime<Utc>,
+    },
+    Typing {
+        room_id: i32,
+        username: String,
+        is_typing: bool,
+    },
+    Reaction {
+        room_id: i32,
+        message_id: i32,
+        emoji: String,
+        count: i64,
+        reacted_by: Vec<String>,
+    },
+    ReadReceipt {
+        room_id: i32,
+        username: String,
+        message_id: i32,
+    },
+    Mention {
+        room_id: i32,
+        message_id: i32,
+        mentioned: Vec<String>,
+    },
+    Presence {
+        online: Vec<String>,
+    },
+    ResyncRequired {
+        skipped: u64,
+        reconnect_after_ms: u64,
+    },
+    TokenExpired,
+    // the operator's greeting, on connect and whenever an admin changes it
+    Motd {
+        text: String,
+    },
+    // the user opened another socket and SINGLE_SESSION is on; this one closes
+    SessionSuperseded,
+    // answers a reauth; `exp` is when the new token lapses
+    Reauthenticated {
+        exp: usize,
+    },
+    // `truncated` means older (on connect) or newer (on resync) messages
+    // exist than the ones included
+    History {
+        messages: Vec<ChatMessage>,
+        truncated: bool,
+    },
+    // never stored; only ever broadcast live
+    System {
+        kind: SystemKind,
+        username: String,
+    },
+    // sent to one client when something it sent failed; `code` is for
+    // programs, `message` for people
+    Error {
+        code: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+    },
+    // sent to the sender only, once its new_message is stored
+    Ack {
+        client_msg_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+        message: ChatMessage,
+    },
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+}
+
+impl OutgoingMessage {
+    // tells a client how many frames it lost, and when to come back for them
+    fn resync_required(skipped: u64) -> Self {
+        OutgoingMessage::ResyncRequired {
+            skipped,
+            reconnect_after_ms: jitter_ms(0, config::get().resync_jitter),
+        }
+    }
+
+    // the shutdown frame is broadcast once with the base delay; each socket
+    // adds its own jitter on the way out
+    fn jittered(self) -> Self {
+        match self {
+            OutgoingMessage::ServerShutdown { reconnect_after_ms } => {
+                OutgoingMessage::ServerShutdown {
+                    reconnect_after_ms: jitter_ms(
+                        reconnect_after_ms,
+                        config::get().reconnect_jitter,
+                    ),
+                }
+            }
+            msg => msg,
+        }
+    }
+
+    // the room this frame belongs to, or None for frames every client receives
+    pub fn room_id(&self) -> Option<i32> {
+        match self {
+            OutgoingMessage::NewMessage(msg) => Some(msg.room_id),
+            OutgoingMessage::Delete { room_id, .. } => Some(*room_id),
+            OutgoingMessage::Edit { room_id, .. } => Some(*room_id),
+            OutgoingMessage::Typing { room_id, .. } => Some(*room_id),
+            OutgoingMessage::Reaction { room_id, .. } => Some(*room_id),
+            OutgoingMessage::ReadReceipt { room_id, .. } => Some(*room_id),
+            OutgoingMessage::Mention { room_id, .. } => Some(*room_id),
+            _ => None,
+        }
+    }
+}
+
+fn jitter_ms(base_ms: u64, window: Duration) -> u64 {
+    base_ms + rand::random_range(0..=window.as_millis() as u64)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemKind {
+    Join,
+    Leave,
+}
+
+#[derive(Debug, Error)]
+pub enum ChatError {
+    #[error(transparent)]
+    Auth(#[from] TokenError),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("storage error: {0}")]
+    Storage(#[from] std::io::Error),
+    #[error("bad request: {0}")]
+    BadRequest(&'static str),
+    #[error("invalid payload: {0}")]
+    InvalidPayload(serde_json::Error),
+    #[error("unknown action")]
+    UnknownAction,
+    #[error("forbidden: {0}")]
+    Forbidden(&'static str),
+    #[error("not found: {0}")]
+    NotFound(&'static str),
+    #[error("too large: {0}")]
+    TooLarge(&'static str),
+    #[error("rate limited")]
+    RateLimited,
+}
+
+impl ChatError {
+    // human-readable, and safe to show: internal failures stay vague
+    pub fn message(&self) -> String {
+        match self {
+            ChatError::Database(_) | ChatError::Storage(_) => "internal error".to_string(),
+            ChatError::BadRequest(code)
+            | ChatError::Forbidden(code)
+            | ChatError::NotFound(code)
+            | ChatError::TooLarge(code) => code.replace('_', " "),
+            e => e.to_string(),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::Auth(e) => e.code(),
+            ChatError::Database(_) | ChatError::Storage(_) => "internal_error",
+            ChatError::BadRequest(code)
+            | ChatError::Forbidden(code)
+            | ChatError::NotFound(code)
+            | ChatError::TooLarge(code) => code,
+            ChatError::InvalidPayload(_) => "invalid_payload",
+            ChatError::UnknownAction => "unknown_action",
+            ChatError::RateLimited => "rate_limited",
+        }
+    }
+}
+
+impl ResponseError for ChatError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChatError::Auth(e) => e.status_code(),
+            ChatError::Database(_) | ChatError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ChatError::BadRequest(_) | ChatError::InvalidPayload(_) | ChatError::UnknownAction => {
+                StatusCode::BAD_REQUEST
+            }
+            ChatError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ChatError::NotFound(_) => StatusCode::NOT_FOUND,
+            ChatError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ChatError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(serde_json::json!({ "error": self.code() }))
+    }
+}
+
+pub const DEFAULT_ROOM: &str = "general";
+
+// how long shutdown waits for open sockets to finish their current action and
+// close, and how long clients are told to wait before reconnecting
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const SHUTDOWN_RECONNECT_AFTER: Duration = Duration::from_secs(5);
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_NONCE_LEN: usize = 64;
+const MAX_RESYNC_MESSAGES: i64 = 500;
+// a user who comes back within this long (a page refresh, a flaky network)
+// gets neither a leave nor a join message
+const JOIN_LEAVE_DEBOUNCE: Duration = Duration::from_secs(5);
+
+pub struct AppState {
+    pub db_pool: PgPool,
+    pub tx: broadcast::Sender<OutgoingMessage>,
+    // open connections per username, so several tabs count as one presence
+    pub online: Mutex<HashMap<String, usize>>,
+    pub max_message_length: usize,
+    pub sanitize_mode: SanitizeMode,
+    pub expand_shortcodes: bool,
+    pub word_filter: Option<WordFilter>,
+    pub shutting_down: AtomicBool,
+    pub uploads: Arc<dyn UploadStore>,
+    pub observer: Arc<dyn ConnectionObserver>,
+    // open websockets, capped at max_ws_connections
+    connections: AtomicUsize,
+    max_ws_connections: usize,
+    join_leave_messages: bool,
+    multi_instance: bool,
+    // users whose last connection closed less than JOIN_LEAVE_DEBOUNCE ago,
+    // with when it closed; their leave message hasn't been sent yet
+    pending_leaves: Mutex<HashMap<String, Instant>>,
+    // (username, room) of everyone currently typing, with when they last said so
+    typing: Mutex<HashMap<(String, i32), Instant>>,
+    single_session: bool,
+    // with single_session, each user's one live socket by email, and how to
+    // tell it that it has been replaced
+    sessions: Mutex<HashMap<String, (u64, Arc<Notify>)>>,
+    next_session_id: AtomicU64,
+    // starts out as MOTD / MOTD_PATH and lives in memory after that
+    motd: Mutex<Option<String>>,
+}
+
+impl AppState {
+    pub fn new(
+        db_pool: PgPool,
+        tx: broadcast::Sender<OutgoingMessage>,
+        word_filter: Option<WordFilter>,
+    ) -> Self {
+        Self {
+            db_pool,
+            tx,
+            online: Mutex::new(HashMap::new()),
+            max_message_length: config::get().max_message_length,
+            sanitize_mode: config::get().sanitize_mode,
+            expand_shortcodes: config::get().expand_shortcodes,
+            word_filter,
+            shutting_down: AtomicBool::new(false),
+            uploads: Arc::new(DiskStore::new()),
+            observer: observer::from_config(),
+            connections: AtomicUsize::new(0),
+            max_ws_connections: config::get().max_ws_connections,
+            join_leave_messages: config::get().join_leave_messages,
+            multi_instance: config::get().multi_instance,
+            pending_leaves: Mutex::new(HashMap::new()),
+            typing: Mutex::new(HashMap::new()),
+            single_session: config::get().single_session,
+            sessions: Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(0),
+            motd: Mutex::new(config::get().motd.clone()),
+        }
+    }
+
+    pub fn online_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.online.lock().unwrap().keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    // returns true when this is the user's first open connection
+    fn connect(&self, username: &str) -> bool {
+        let mut online = self.online.lock().unwrap();
+        let count = online.entry(username.to_string()).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    // returns true when the user's last open connection closed
+    fn disconnect(&self, username: &str) -> bool {
+        let mut online = self.online.lock().unwrap();
+        match online.get_mut(username) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                online.remove(username);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn motd(&self) -> Option<String> {
+        self.motd.lock().unwrap().clone()
+    }
+
+    // open sockets get a new text right away; clearing it only affects
+    // sockets that connect later
+    pub fn set_motd(&self, text: Option<String>) {
+        *self.motd.lock().unwrap() = text.clone();
+        if let Some(text) = text {
+            let _ = self.tx.send(OutgoingMessage::Motd { text });
+        }
+    }
+
+    // makes a new socket the user's only one, telling the socket it replaces
+    // to close. None unless SINGLE_SESSION is on
+    fn start_session(&self, email: &str) -> Option<(u64, Arc<Notify>)> {
+        if !self.single_session {
+            return None;
+        }
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let superseded = Arc::new(Notify::new());
+        let previous = self
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), (id, superseded.clone()));
+        if let Some((_, previous)) = previous {
+            // notify_one keeps the permit if the old socket isn't waiting yet
+            previous.notify_one();
+        }
+        Some((id, superseded))
+    }
+
+    // leaves the entry alone when a newer socket has already taken it over
+    fn end_session(&self, email: &str, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(email)
+            .is_some_and(|(current, _)| *current == id)
+        {
+            sessions.remove(email);
+        }
+    }
+
+    // message changes go through here rather than straight to `tx` so that,
+    // with MULTI_INSTANCE, sockets on the other instances hear about them too
+    async fn broadcast_message(&self, msg: OutgoingMessage) {
+        if self.multi_instance {
+            fanout::publish(&self.db_pool, &msg).await;
+        }
+        // e.g. POST /messages while no socket is open; the change is stored anyway
+        if let Err(broadcast::error::SendError(msg)) = self.tx.send(msg) {
+            tracing::debug!(room_id = ?msg.room_id(), "broadcast had no live receivers");
+        }
+    }
+
+    // called when the user's first connection opens
+    fn announce_join(&self, username: &str) {
+        if !self.join_leave_messages {
+            return;
+        }
+        // back before their leave went out, so as far as others know they never left
+        if self
+            .pending_leaves
+            .lock()
+            .unwrap()
+            .remove(username)
+            .is_some()
+        {
+            return;
+        }
+        let _ = self.tx.send(OutgoingMessage::System {
+            kind: SystemKind::Join,
+            username: username.to_string(),
+        });
+    }
+
+    // called when the user's last connection closes; the leave is held back
+    // for JOIN_LEAVE_DEBOUNCE in case they reconnect
+    fn announce_leave(self: &Arc<Self>, username: &str) {
+        if !self.join_leave_messages || self.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+        let left_at = Instant::now();
+        self.pending_leaves
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), left_at);
+
+        let state = self.clone();
+        let username = username.to_string();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(JOIN_LEAVE_DEBOUNCE).await;
+            {
+                let mut pending = state.pending_leaves.lock().unwrap();
+                // gone if they came back, newer if they came back and left again
+                if pending.get(&username) != Some(&left_at) {
+                    return;
+                }
+                pending.remove(&username);
+            }
+            let _ = state.tx.send(OutgoingMessage::System {
+                kind: SystemKind::Leave,
+                username,
+            });
+        });
+    }
+
+    // (re)starts the user's typing timer; if no further typing event arrives
+    // within TYPING_TIMEOUT, everyone is told they stopped
+    fn start_typing(self: &Arc<Self>, username: &str, room_id: i32) {
+        let key = (username.to_string(), room_id);
+        let started_at = Instant::now();
+        self.typing.lock().unwrap().insert(key.clone(), started_at);
+
+        let state = self.clone();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(TYPING_TIMEOUT).await;
+            {
+                let mut typing = state.typing.lock().unwrap();
+                // a newer event restarted the timer, or typing already stopped
+                if typing.get(&key) != Some(&started_at) {
+                    return;
+                }
+                typing.remove(&key);
+            }
+            let (username, room_id) = key;
+            let _ = state.tx.send(OutgoingMessage::Typing {
+                room_id,
+                username,
+                is_typing: false,
+            });
+        });
+    }
+
+    // returns true if the user was typing
+    fn stop_typing(&self, username: &str, room_id: i32) -> bool {
+        self.typing
+            .lock()
+            .unwrap()
+            .remove(&(username.to_string(), room_id))
+            .is_some()
+    }
+
+    fn broadcast_presence(&self) {
+        let _ = self.tx.send(OutgoingMessage::Presence {
+            online: self.online_users(),
+        });
+    }
+
+    // claims one of the MAX_WS_CONNECTIONS slots; it's given back when the
+    // returned guard is dropped
+    fn try_acquire_connection(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max_ws_connections).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(self.clone()))
+    }
+
+    fn has_connections(&self) -> bool {
+        !self.online.lock().unwrap().is_empty()
+    }
+
+    // tells every socket to reconnect later, then waits up to `grace` for them
+    // to close so actions already being handled can finish writing
+    pub async fn shutdown(&self, grace: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let _ = self.tx.send(OutgoingMessage::ServerShutdown {
+            reconnect_after_ms: SHUTDOWN_RECONNECT_AFTER.as_millis() as u64,
+        });
+
+        let deadline = Instant::now() + grace;
+        while self.has_connections() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+// websocket
+const TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+const TYPING_DEBOUNCE: Duration = Duration::from_secs(1);
+// a user who said they're typing and then went quiet (or vanished) is
+// reported as stopped after this long
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+// users.last_seen is written at most this often per connection while it's active
+const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
+const SSE_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// broadcast frames buffered for one websocket that isn't reading fast enough;
+// past this the oldest are dropped and the client is told to resync
+const OUTBOUND_QUEUE_CAPACITY: usize = 128;
+
+const MESSAGE_BURST: f64 = 5.0;
+const MESSAGE_REFILL_PERIOD: Duration = Duration::from_secs(2);
+
+// allows MESSAGE_BURST messages per MESSAGE_REFILL_PERIOD, refilling continuously
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self {
+            tokens: MESSAGE_BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let rate = MESSAGE_BURST / MESSAGE_REFILL_PERIOD.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(MESSAGE_BURST);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// one of the MAX_WS_CONNECTIONS slots, held by an open websocket or SSE
+// stream; dropping it gives the slot back
+struct ConnectionSlot(Arc<AppState>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct OutboxQueue {
+    frames: VecDeque<String>,
+    // frames lost since the writer last caught up, reported as resync_required
+    dropped: u64,
+    closed: bool,
+}
+
+// broadcast frames on their way to one websocket. the connection loop only
+// pushes, so a client with a full socket buffer stalls its own writer task and
+// nothing else
+#[derive(Default)]
+struct Outbox {
+    queue: Mutex<OutboxQueue>,
+    notify: Notify,
+}
+
+impl Outbox {
+    fn push(&self, frame: String) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.frames.len() >= OUTBOUND_QUEUE_CAPACITY {
+            queue.frames.pop_front();
+            queue.dropped += 1;
+        }
+        queue.frames.push_back(frame);
+        drop(queue);
+        self.notify.notify_one();
+    }
+
+    // frames that never made it into the queue, e.g. after a broadcast lag
+    fn skip(&self, count: u64) {
+        self.queue.lock().unwrap().dropped += count;
+        self.notify.notify_one();
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    fn next(&self) -> Option<Option<String>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return None;
+        }
+        if queue.dropped > 0 {
+            let skipped = std::mem::take(&mut queue.dropped);
+            let frame = OutgoingMessage::resync_required(skipped);
+            return Some(Some(serde_json::to_string(&frame).unwrap()));
+        }
+        Some(queue.frames.pop_front())
+    }
+
+    async fn run_writer(&self, mut session: Session, username: String) {
+        while let Some(next) = self.next() {
+            match next {
+                Some(frame) => {
+                    if let Err(e) = session.text(frame).await {
+                        tracing::warn!(error = %e, username = %username, "failed to send websocket frame");
+                        return;
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+// per-connection state shared by the websocket action handlers
+struct Connection {
+    email: String,
+    username: String,
+    is_moderator: bool,
+    room_id: i32,
+    state: Arc<AppState>,
+    session: Session,
+    last_typing: Option<(bool, Instant)>,
+    message_limiter: TokenBucket,
+    // newest message already delivered in the history frame
+    history_until: Option<i32>,
+    // expiry of the token the socket is currently authorized by
+    exp: usize,
+    ip: String,
+    // set with SINGLE_SESSION: this socket's id and what fires once another
+    // socket for the same user replaces it
+    session_slot: Option<(u64, Arc<Notify>)>,
+    last_seen_written: Option<Instant>,
+    outbox: Arc<Outbox>,
+    _slot: ConnectionSlot,
+}
+
+impl Connection {
+    // throttled so a chatty client doesn't cost a write per frame
+    fn touch_last_seen(&mut self) {
+        if self
+            .last_seen_written
+            .is_some_and(|written| written.elapsed() < LAST_SEEN_INTERVAL)
+        {
+            return;
+        }
+        self.last_seen_written = Some(Instant::now());
+        let pool = self.state.db_pool.clone();
+        let username = self.username.clone();
+        actix_rt::spawn(async move { record_last_seen(&pool, &username).await });
+    }
+
+    async fn send_error(&mut self, code: &str, message: String, client_msg_id: Option<String>) {
+        let frame = OutgoingMessage::Error {
+            code: code.to_string(),
+            message,
+            client_msg_id,
+        };
+        let _ = self
+            .session
+            .text(serde_json::to_string(&frame).unwrap())
+            .await;
+    }
+}
+
+// whether a listener in `room_id` should get `msg`; new messages up to
+// `history_until` were already delivered with the history frame
+fn is_visible_to(
+    msg: &OutgoingMessage,
+    username: &str,
+    room_id: i32,
+    history_until: Option<i32>,
+) -> bool {
+    if let OutgoingMessage::DirectMessage(dm) = msg {
+        return dm.sender == username || dm.recipient == username;
+    }
+    if msg.room_id().is_some_and(|msg_room| msg_room != room_id) {
+        return false;
+    }
+    if let OutgoingMessage::NewMessage(new_msg) = msg
+        && new_msg.id <= history_until
+    {
+        return false;
+    }
+    !matches!(msg, OutgoingMessage::Typing { username: typist, .. } if typist == username)
+}
+
+async fn find_room(pool: &PgPool, name: &str) -> Result<Option<i32>, sqlx::Error> {
+    time_query(
+        "find_room",
+        sqlx::query_scalar::<_, i32>("SELECT id FROM rooms WHERE name = $1")
+            .bind(name)
+            .fetch_optional(pool),
+    )
+    .await
+}
+
+pub async fn require_room(pool: &PgPool, name: &str) -> Result<i32, ChatError> {
+    find_room(pool, name)
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, room = name, "failed to look up room"))?
+        .ok_or(ChatError::NotFound("room_not_found"))
+}
+
+impl MessageCursor {
+    fn of(msg: &ChatMessage) -> Option<Self> {
+        msg.id.map(|id| MessageCursor {
+            time: Some(msg.time),
+            id,
+        })
+    }
+
+    fn parse(cursor: &str) -> Option<Self> {
+        let Some((micros, id)) = cursor.split_once('_') else {
+            return Some(MessageCursor {
+                time: None,
+                id: cursor.parse().ok()?,
+            });
+        };
+        Some(MessageCursor {
+            time: Some(DateTime::from_timestamp_micros(micros.parse().ok()?)?),
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+impl Serialize for MessageCursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.time {
+            Some(time) => {
+                serializer.collect_str(&format_args!("{}_{}", time.timestamp_micros(), self.id))
+            }
+            None => serializer.collect_str(&self.id),
+        }
+    }
+}
+
+// by (time, id), newest first unless `ascending`; ties on time are broken by
+// id so pages never overlap or leave a gap. `cursor` is exclusive and, like the
+// order, points backwards or forwards. `room_id`, `username` and the
+// [since, until) range narrow the results when given
+async fn recent_messages(
+    pool: &PgPool,
+    room_id: Option<i32>,
+    username: Option<&str>,
+    range: (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    cursor: Option<MessageCursor>,
+    ascending: bool,
+    limit: i64,
+) -> Result<Vec<ChatMessage>, sqlx::Error> {
+    // only these fixed fragments are spliced in; every value is bound
+    let (cmp, order) = if ascending {
+        (">", "ASC")
+    } else {
+        ("<", "DESC")
+    };
+    let sql = format!(
+        "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce, r.reactions
+        FROM messages
+        LEFT JOIN LATERAL (
+            SELECT jsonb_object_agg(emoji, count) AS reactions
+            FROM (SELECT emoji, COUNT(*) AS count FROM message_reactions
+                WHERE message_id = messages.id GROUP BY emoji) counts
+        ) r ON TRUE
+        WHERE deleted_at IS NULL
+        AND ($1::INT IS NULL OR room_id = $1)
+        AND ($2::TEXT IS NULL OR username = $2)
+        AND ($3::INT IS NULL OR (time, id) {cmp} (COALESCE($4, (SELECT time FROM messages WHERE id = $3)), $3))
+        AND ($5::TIMESTAMPTZ IS NULL OR time >= $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR time < $6)
+        ORDER BY time {order}, id {order}
+        LIMIT $7"
+    );
+    time_query(
+        "recent_messages",
+        sqlx::query_as::<_, ChatMessage>(&sql)
+            .bind(room_id)
+            .bind(username)
+            .bind(cursor.map(|cursor| cursor.id))
+            .bind(cursor.and_then(|cursor| cursor.time))
+            .bind(range.0)
+            .bind(range.1)
+            .bind(limit)
+            .fetch_all(pool),
+    )
+    .await
+}
+
+fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+// every frame from the client counts as a sign of life, pongs included. on
+// tokio's clock, like the heartbeat interval that checks it
+fn client_timed_out(last_heartbeat: time::Instant, now: time::Instant) -> bool {
+    now.duration_since(last_heartbeat) > CLIENT_TIMEOUT
+}
+
+fn token_expired(exp: usize) -> bool {
+    exp as i64 <= Utc::now().timestamp()
+}
+
+fn parse_payload<T: DeserializeOwned>(payload: serde_json::Value) -> Result<T, ChatError> {
+    serde_json::from_value(payload).map_err(ChatError::InvalidPayload)
+}
+
+async fn handle_text(conn: &mut Connection, text: &str) {
+    let ws_msg = match serde_json::from_str::<WebSocketMessage>(text) {
+        Ok(ws_msg) => ws_msg,
+        Err(e) => return conn.send_error("invalid_json", e.to_string(), None).await,
+    };
+
+    // lets the sender match a failed send to its optimistic entry
+    let client_msg_id = match ws_msg.action.as_str() {
+        "new_message" => ws_msg
+            .payload
+            .get("client_msg_id")
+            .and_then(|id| id.as_str())
+            .map(String::from),
+        _ => None,
+    };
+
+    let result = match ws_msg.action.as_str() {
+        "new_message" => match parse_payload(ws_msg.payload) {
+            Ok(new_msg) => handle_new_message(conn, new_msg).await,
+            Err(e) => Err(e),
+        },
+        "delete_message" => match parse_payload(ws_msg.payload) {
+            Ok(delete_req) => handle_delete_message(conn, delete_req).await,
+            Err(e) => Err(e),
+        },
+        "edit_message" => match parse_payload(ws_msg.payload) {
+            Ok(edit_req) => handle_edit_message(conn, edit_req).await,
+            Err(e) => Err(e),
+        },
+        "send_dm" => match parse_payload(ws_msg.payload) {
+            Ok(dm_req) => handle_send_dm(conn, dm_req).await,
+            Err(e) => Err(e),
+        },
+        "react" | "unreact" => match parse_payload(ws_msg.payload) {
+            Ok(reaction_req) => handle_reaction(conn, reaction_req, ws_msg.action == "react").await,
+            Err(e) => Err(e),
+        },
+        "mark_read" => match parse_payload(ws_msg.payload) {
+            Ok(read_req) => handle_mark_read(conn, read_req).await,
+            Err(e) => Err(e),
+        },
+        "resync" => match parse_payload(ws_msg.payload) {
+            Ok(resync_req) => handle_resync(conn, resync_req).await,
+            Err(e) => Err(e),
+        },
+        "typing" => parse_payload(ws_msg.payload).map(|typing_req| handle_typing(conn, typing_req)),
+        "reauth" => match parse_payload(ws_msg.payload) {
+            Ok(reauth_req) => handle_reauth(conn, reauth_req).await,
+            Err(e) => Err(e),
+        },
+        _ => Err(ChatError::UnknownAction),
+    };
+
+    if let Err(e) = result {
+        if let ChatError::Database(err) = &e {
+            tracing::error!(
+                error = %err,
+                action = %ws_msg.action,
+                username = %conn.username,
+                room_id = conn.room_id,
+                "websocket action failed"
+            );
+        }
+        match e {
+            ChatError::InvalidPayload(err) => {
+                let detail = format!("{} payload: {}", ws_msg.action, err);
+                conn.send_error("invalid_payload", detail, client_msg_id)
+                    .await
+            }
+            ChatError::UnknownAction => {
+                let detail = format!("unknown action {:?}", ws_msg.action);
+                conn.send_error("unknown_action", detail, None).await
+            }
+            e => conn.send_error(e.code(), e.message(), client_msg_id).await,
+        }
+    }
+}
+
+// trims trailing whitespace and rejects blank or oversized messages, returning
+// the error code to send back
+pub fn validate_message(message: &str, max_length: usize) -> Result<&str, &'static str> {
+    let message = message.trim_end();
+    if message.trim_start().is_empty() {
+        return Err("empty_message");
+    }
+    if message.chars().count() > max_length {
+        return Err("message_too_long");
+    }
+    Ok(message)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn sanitize_message(message: &str, mode: SanitizeMode) -> String {
+    match mode {
+        SanitizeMode::Escape => escape_html(message),
+        SanitizeMode::Allowlist => ammonia::clean(message),
+        SanitizeMode::Off => message.to_string(),
+    }
+}
+
+// sanitizing happens on write, so whatever is stored and broadcast is already
+// safe to render; the length limit applies to the text as the user typed it
+fn prepare_message(state: &AppState, message: &str) -> Result<String, &'static str> {
+    let message = validate_message(message, state.max_message_length)?;
+    let message = match &state.word_filter {
+        Some(word_filter) => word_filter.apply(message)?,
+        None => message.to_string(),
+    };
+    let clean = if state.expand_shortcodes {
+        sanitize_message(&shortcodes::expand(&message), state.sanitize_mode)
+    } else {
+        sanitize_message(&message, state.sanitize_mode)
+    };
+    if clean.trim().is_empty() {
+        return Err("empty_message");
+    }
+    Ok(clean)
+}
+
+enum Posted {
+    New(ChatMessage),
+    // a retry with a nonce that was already used; nothing was stored
+    Duplicate(ChatMessage),
+}
+
+// validates, stores and broadcasts a room message for both the new_message
+// action and POST /messages. `rate_limit` is only consulted once the message
+// is known to be valid
+async fn post_message(
+    state: &AppState,
+    email: &str,
+    username: &str,
+    room_id: i32,
+    new_msg: NewMessage,
+    rate_limit: impl FnOnce() -> bool,
+) -> Result<Posted, ChatError> {
+    if let Some(url) = &new_msg.attachment_url
+        && !state.uploads.owns_url(url)
+    {
+        return Err(ChatError::BadRequest("invalid_attachment"));
+    }
+
+    // an attachment may be sent without any text
+    let message = match prepare_message(state, &new_msg.message) {
+        Ok(message) => message,
+        Err("empty_message") if new_msg.attachment_url.is_some() => String::new(),
+        Err(code) => return Err(ChatError::BadRequest(code)),
+    };
+
+    // replies stay within the room and can't target deleted messages
+    if let Some(parent_id) = new_msg.reply_to {
+        match fetch_message(&state.db_pool, parent_id).await {
+            Ok(parent) if parent.room_id == room_id => {}
+            Ok(_) | Err(ChatError::NotFound(_)) => {
+                return Err(ChatError::BadRequest("invalid_reply"));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if new_msg
+        .nonce
+        .as_ref()
+        .is_some_and(|nonce| nonce.is_empty() || nonce.len() > MAX_NONCE_LEN)
+    {
+        return Err(ChatError::BadRequest("invalid_nonce"));
+    }
+    if new_msg
+        .client_msg_id
+        .as_ref()
+        .is_some_and(|id| id.is_empty() || id.len() > MAX_NONCE_LEN)
+    {
+        return Err(ChatError::BadRequest("invalid_client_msg_id"));
+    }
+
+    if !rate_limit() {
+        return Err(ChatError::RateLimited);
+    }
+
+    let inserted = time_query(
+        "insert_message",
+        sqlx::query_as::<_, ChatMessage>(
+            "INSERT INTO messages (email, username, message, room_id, attachment_url, parent_id, nonce) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (email, nonce) DO NOTHING
+            RETURNING *",
+        )
+        .bind(email)
+        .bind(username)
+        .bind(&message)
+        .bind(room_id)
+        .bind(&new_msg.attachment_url)
+        .bind(new_msg.reply_to)
+        .bind(&new_msg.nonce)
+        .fetch_optional(&state.db_pool),
+    )
+    .await?;
+
+    let Some(saved_msg) = inserted else {
+        let existing = time_query(
+            "find_duplicate_message",
+            sqlx::query_as::<_, ChatMessage>(
+                "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages
+                WHERE email = $1 AND nonce = $2",
+            )
+            .bind(email)
+            .bind(&new_msg.nonce)
+            .fetch_one(&state.db_pool),
+        )
+        .await?;
+        return Ok(Posted::Duplicate(existing));
+    };
+
+    METRICS.messages_sent_total.inc();
+    // the message is already saved, so a failure here only costs the highlight
+    let mention = mentions::record_mentions(&state.db_pool, &saved_msg)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!(error = %e, "failed to record mentions");
+            None
+        });
+    state
+        .broadcast_message(OutgoingMessage::NewMessage(saved_msg.clone()))
+        .await;
+    if let Some(mention) = mention {
+        let _ = state.tx.send(mention);
+    }
+    Ok(Posted::New(saved_msg))
+}
+
+async fn handle_new_message(conn: &mut Connection, new_msg: NewMessage) -> Result<(), ChatError> {
+    let client_msg_id = new_msg.client_msg_id.clone();
+    let posted = post_message(
+        &conn.state,
+        &conn.email,
+        &conn.username,
+        conn.room_id,
+        new_msg,
+        || conn.message_limiter.try_take(),
+    )
+    .await?;
+
+    let saved = match posted {
+        Posted::New(saved) => saved,
+        // everyone else has already seen a duplicate, so only the sender gets it again
+        Posted::Duplicate(existing) => {
+            let frame = serde_json::to_string(&OutgoingMessage::NewMessage(existing.clone()));
+            let _ = conn.session.text(frame.unwrap()).await;
+            existing
+        }
+    };
+    if let Some(client_msg_id) = client_msg_id {
+        let ack = OutgoingMessage::Ack {
+            client_msg_id,
+            nonce: saved.nonce.clone(),
+            message: saved,
+        };
+        let _ = conn
+            .session
+            .text(serde_json::to_string(&ack).unwrap())
+            .await;
+    }
+    Ok(())
+}
+
+async fn handle_send_dm(conn: &mut Connection, mut dm_req: SendDmRequest) -> Result<(), ChatError> {
+    dm_req.message =
+        prepare_message(&conn.state, &dm_req.message).map_err(ChatError::BadRequest)?;
+
+    if !conn.message_limiter.try_take() {
+        return Err(ChatError::RateLimited);
+    }
+
+    let saved_dm =
+        dm::save_direct_message(&conn.state.db_pool, &conn.email, &conn.username, dm_req)
+            .await?
+            .ok_or(ChatError::NotFound("user_not_found"))?;
+
+    let _ = conn.state.tx.send(OutgoingMessage::DirectMessage(saved_dm));
+    Ok(())
+}
+
+async fn handle_reaction(
+    conn: &mut Connection,
+    reaction_req: ReactionRequest,
+    add: bool,
+) -> Result<(), ChatError> {
+    if !reactions::is_valid_emoji(&reaction_req.emoji) {
+        return Err(ChatError::BadRequest("invalid_emoji"));
+    }
+
+    let update = if add {
+        reactions::add_reaction(&conn.state.db_pool, &conn.email, reaction_req).await?
+    } else {
+        reactions::remove_reaction(&conn.state.db_pool, &conn.email, reaction_req).await?
+    }
+    .ok_or(ChatError::NotFound("message_not_found"))?;
+
+    let _ = conn.state.tx.send(update);
+    Ok(())
 }
 
-pub struct AppState {
-    pub db_pool: PgPool,
-    pub tx: broadcast::Sender<OutgoingMessage>,
+async fn handle_mark_read(
+    conn: &mut Connection,
+    read_req: MarkReadRequest,
+) -> Result<(), ChatError> {
+    let receipt = receipts::mark_read(&conn.state.db_pool, &conn.email, &conn.username, read_req)
+        .await?
+        .ok_or(ChatError::NotFound("message_not_found"))?;
+
+    if let Some(receipt) = receipt {
+        let _ = conn.state.tx.send(receipt);
+    }
+    Ok(())
+}
+
+async fn fetch_message(pool: &PgPool, id: i32) -> Result<ChatMessage, ChatError> {
+    time_query(
+        "fetch_message",
+        sqlx::query_as::<_, ChatMessage>(
+            "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .fetch_optional(pool),
+    )
+    .await?
+    .ok_or(ChatError::NotFound("message_not_found"))
+}
+
+// authors can delete their own messages, admins and moderators anyone's
+fn can_delete(msg: &ChatMessage, email: &str, is_moderator: bool) -> bool {
+    msg.email == email || is_moderator
+}
+
+async fn handle_delete_message(
+    conn: &mut Connection,
+    delete_req: DeleteMessageRequest,
+) -> Result<(), ChatError> {
+    let msg = fetch_message(&conn.state.db_pool, delete_req.id).await?;
+    if !can_delete(&msg, &conn.email, conn.is_moderator) {
+        return Err(ChatError::Forbidden("not_message_owner"));
+    }
+
+    time_query(
+        "delete_message",
+        sqlx::query("UPDATE messages SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(delete_req.id)
+            .execute(&conn.state.db_pool),
+    )
+    .await?;
+
+    METRICS.messages_deleted_total.inc();
+    if msg.email != conn.email {
+        audit::record(
+            &conn.state.db_pool,
+            Some(&conn.email),
+            Action::MessageDelete,
+            Some(&delete_req.id.to_string()),
+            &conn.ip,
+        )
+        .await;
+    }
+    conn.state
+        .broadcast_message(OutgoingMessage::Delete {
+            room_id: msg.room_id,
+            message_id: delete_req.id,
+        })
+        .await;
+    Ok(())
+}
+
+async fn handle_edit_message(
+    conn: &mut Connection,
+    mut edit_req: EditMessageRequest,
+) -> Result<(), ChatError> {
+    edit_req.message =
+        prepare_message(&conn.state, &edit_req.message).map_err(ChatError::BadRequest)?;
+
+    let msg = fetch_message(&conn.state.db_pool, edit_req.id).await?;
+    if msg.email != conn.email {
+        return Err(ChatError::Forbidden("not_message_owner"));
+    }
+
+    let edited_at = time_query(
+        "edit_message",
+        sqlx::query_scalar::<_, DateTime<Utc>>(
+            "UPDATE messages SET message = $1, edited_at = CURRENT_TIMESTAMP WHERE id = $2 RETURNING edited_at",
+        )
+        .bind(&edit_req.message)
+        .bind(edit_req.id)
+        .fetch_one(&conn.state.db_pool),
+    )
+    .await?;
+
+    conn.state
+        .broadcast_message(OutgoingMessage::Edit {
+            room_id: msg.room_id,
+            message_id: edit_req.id,
+            message: edit_req.message,
+            edited_at,
+        })
+        .await;
+    Ok(())
 }
 
-// mods
-pub async fn create_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS messages (
-            id SERIAL PRIMARY KEY,
-            email VARCHAR(255) NOT NULL REFERENCES users(email),
-            username VARCHAR(255) NOT NULL REFERENCES users(username),
-            message TEXT NOT NULL,
-            time TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+// replays the room's messages after `since_id`, oldest first, to the sender
+// only. a client that was further behind than MAX_RESYNC_MESSAGES gets
+// `truncated` and should reload instead
+async fn handle_resync(conn: &mut Connection, resync_req: ResyncRequest) -> Result<(), ChatError> {
+    let mut messages = time_query(
+        "resync_messages",
+        sqlx::query_as::<_, ChatMessage>(
+            "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages
+            WHERE deleted_at IS NULL AND room_id = $1 AND id > $2
+            ORDER BY id
+            LIMIT $3",
         )
-        "#,
+        .bind(conn.room_id)
+        .bind(resync_req.since_id)
+        .bind(MAX_RESYNC_MESSAGES + 1)
+        .fetch_all(&conn.state.db_pool),
     )
-    .execute(pool)
     .await?;
+
+    let truncated = messages.len() as i64 > MAX_RESYNC_MESSAGES;
+    messages.truncate(MAX_RESYNC_MESSAGES as usize);
+    let frame = serde_json::to_string(&OutgoingMessage::History {
+        messages,
+        truncated,
+    })
+    .unwrap();
+    let _ = conn.session.text(frame).await;
+    Ok(())
+}
+
+// typing events are ephemeral: never stored, and repeats of the same state
+// within TYPING_DEBOUNCE are coalesced into the first one
+fn handle_typing(conn: &mut Connection, typing_req: TypingRequest) {
+    // repeats refresh the timeout even when they aren't rebroadcast
+    if typing_req.is_typing {
+        conn.state.start_typing(&conn.username, conn.room_id);
+    } else {
+        conn.state.stop_typing(&conn.username, conn.room_id);
+    }
+
+    let now = Instant::now();
+    if let Some((is_typing, at)) = conn.last_typing
+        && is_typing == typing_req.is_typing
+        && now.duration_since(at) < TYPING_DEBOUNCE
+    {
+        return;
+    }
+    conn.last_typing = Some((typing_req.is_typing, now));
+
+    let _ = conn.state.tx.send(OutgoingMessage::Typing {
+        room_id: conn.room_id,
+        username: conn.username.clone(),
+        is_typing: typing_req.is_typing,
+    });
+}
+
+// swaps in a refreshed access token so the socket outlives the one it was
+// opened with. a token for another account is refused, so a socket can't be
+// taken over mid-session. the username stays as connected, since presence
+// and typing state are keyed by it
+async fn handle_reauth(conn: &mut Connection, reauth_req: ReauthRequest) -> Result<(), ChatError> {
+    let claims = verify_session(&conn.state.db_pool, reauth_req.token).await?;
+    if claims.sub != conn.email {
+        tracing::warn!(username = %conn.username, "websocket reauth with another account's token");
+        return Err(ChatError::Forbidden("account_mismatch"));
+    }
+
+    conn.exp = claims.exp;
+    conn.is_moderator = claims.is_moderator();
+    let frame = serde_json::to_string(&OutgoingMessage::Reauthenticated { exp: conn.exp }).unwrap();
+    let _ = conn.session.text(frame).await;
     Ok(())
 }
 
+// drives one websocket until it should be closed, returning the close reason
+async fn run_connection(
+    conn: &mut Connection,
+    rx: &mut broadcast::Receiver<OutgoingMessage>,
+    msg_stream: &mut MessageStream,
+) -> Option<CloseReason> {
+    let mut token_check = interval(TOKEN_CHECK_INTERVAL);
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    let mut last_heartbeat = time::Instant::now();
+    let superseded = conn.session_slot.as_ref().map(|(_, notify)| notify.clone());
+
+    loop {
+        tokio::select! {
+            broadcast = rx.recv() => {
+                let msg = match broadcast {
+                    Ok(msg) => msg,
+                    // a slow client missed some frames; keep it connected
+                    // and let it re-fetch what it skipped
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(username = %conn.username, skipped, "websocket client lagged");
+                        conn.outbox.skip(skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if !is_visible_to(&msg, &conn.username, conn.room_id, conn.history_until) {
+                    continue;
+                }
+                // skips the queue so it isn't stuck behind a backlog the
+                // client is about to abandon anyway
+                if matches!(msg, OutgoingMessage::ServerShutdown { .. }) {
+                    let frame = serde_json::to_string(&msg.jittered()).unwrap();
+                    let _ = conn.session.text(frame).await;
+                    return Some(CloseCode::Restart.into());
+                }
+                conn.outbox.push(serde_json::to_string(&msg).unwrap());
+            }
+            incoming = msg_stream.next() => {
+                let msg = match incoming {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        tracing::info!(error = %e, username = %conn.username, "websocket protocol error");
+                        return Some(CloseCode::Protocol.into());
+                    }
+                    None => {
+                        tracing::info!(username = %conn.username, "websocket closed without a close frame");
+                        return None;
+                    }
+                };
+                last_heartbeat = time::Instant::now();
+                match msg {
+                    Message::Text(text) => {
+                        conn.touch_last_seen();
+                        handle_text(conn, &text).await
+                    }
+                    // the protocol is json in single text frames; say so
+                    // rather than drop anything else without a word
+                    Message::Binary(_) => {
+                        let detail = "binary frames are not supported, send json as text".to_string();
+                        conn.send_error("unsupported_frame", detail, None).await
+                    }
+                    // answered once per fragmented message, on its first frame
+                    Message::Continuation(Item::FirstText(_) | Item::FirstBinary(_)) => {
+                        let detail = "fragmented messages are not supported".to_string();
+                        conn.send_error("unsupported_frame", detail, None).await
+                    }
+                    Message::Ping(bytes) => {
+                        if conn.session.pong(&bytes).await.is_err() {
+                            return None;
+                        }
+                    }
+                    Message::Close(reason) => {
+                        tracing::info!(
+                            username = %conn.username,
+                            code = reason.as_ref().map(|r| u16::from(r.code)),
+                            reason = reason.as_ref().and_then(|r| r.description.as_deref()),
+                            "websocket closed by client"
+                        );
+                        // echoed back to complete the closing handshake
+                        return reason;
+                    }
+                    _ => {}
+                }
+            }
+            _ = heartbeat.tick() => {
+                // a client that vanished without a close frame stops
+                // answering pings; drop it so its receiver is released
+                if client_timed_out(last_heartbeat, time::Instant::now()) {
+                    tracing::info!(username = %conn.username, "websocket client timed out");
+                    return None;
+                }
+                if conn.session.ping(b"").await.is_err() {
+                    return None;
+                }
+            }
+            _ = async {
+                match &superseded {
+                    Some(notify) => notify.notified().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                tracing::info!(username = %conn.username, "websocket superseded by a newer session");
+                let frame = serde_json::to_string(&OutgoingMessage::SessionSuperseded).unwrap();
+                let _ = conn.session.text(frame).await;
+                return Some(CloseCode::Normal.into());
+            }
+            _ = token_check.tick() => {
+                if token_expired(conn.exp) {
+                    let frame = serde_json::to_string(&OutgoingMessage::TokenExpired).unwrap();
+                    let _ = conn.session.text(frame).await;
+                    return Some(CloseCode::Normal.into());
+                }
+            }
+        }
+    }
+}
+
 // routes
 #[get("/ws")]
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
     state: web::Data<Arc<AppState>>,
-) -> Result<HttpResponse, Error> {
-    let token = match req.cookie("token") {
-        Some(token) => token.value().to_string(),
-        None => return Ok(HttpResponse::Unauthorized().finish()),
-    };
+    query: web::Query<RoomQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (claims, via_subprotocol) = authenticate_ws(&req, &state.db_pool).await?;
+
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "error": "shutting_down" })));
+    }
 
-    let claims = match verify_token(token) {
-        Ok(claims) => claims,
-        Err(_) => return Ok(HttpResponse::Unauthorized().finish()),
+    let Some(slot) = state.try_acquire_connection() else {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "error": "too_many_connections" })));
     };
 
-    let email = claims.sub.clone();
-    let username = claims.email.clone();
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room_id = require_room(&state.db_pool, room).await?;
 
-    let (response, session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    // subscribe before taking the snapshot so nothing falls between the two;
+    // messages already in the snapshot are skipped by is_visible_to
+    let rx = state.tx.subscribe();
+    let history_limit = config::get().history_on_connect;
+    let mut history = if history_limit > 0 {
+        recent_messages(
+            &state.db_pool,
+            Some(room_id),
+            None,
+            (None, None),
+            None,
+            false,
+            history_limit + 1,
+        )
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, room, "failed to fetch history"))
+        .map_err(ChatError::from)?
+    } else {
+        Vec::new()
+    };
+    let history_truncated = history.len() as i64 > history_limit;
+    history.truncate(history_limit as usize);
+    let history_until = history.iter().filter_map(|m| m.id).max();
+    history.reverse();
+
+    let (mut response, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    // a client that offers subprotocols drops the connection unless one is echoed
+    if via_subprotocol {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(WS_BEARER_PROTOCOL),
+        );
+    }
+    METRICS.ws_connections_total.inc();
+    METRICS.ws_connections_active.inc();
 
-    let db_pool = state.db_pool.clone();
-    let tx = state.tx.clone();
-    let mut rx = tx.subscribe();
+    let mut conn = Connection {
+        email: claims.sub.clone(),
+        username: claims.email.clone(),
+        is_moderator: claims.is_moderator(),
+        room_id,
+        state: state.get_ref().clone(),
+        session,
+        last_typing: None,
+        message_limiter: TokenBucket::new(),
+        history_until,
+        exp: claims.exp,
+        ip: client_ip(&req),
+        session_slot: state.start_session(&claims.sub),
+        last_seen_written: None,
+        outbox: Arc::new(Outbox::default()),
+        _slot: slot,
+    };
+    let compress = query.compress;
 
-    let mut broadcast_session = session.clone();
-    let mut message_session = session;
+    if state.connect(&conn.username) {
+        state.broadcast_presence();
+        state.announce_join(&conn.username);
+    }
+    state.observer.on_connect(&conn.username);
+    conn.touch_last_seen();
+    let motd = state.motd();
 
     actix_rt::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if let Err(e) = broadcast_session
-                .text(serde_json::to_string(&msg).unwrap())
-                .await
-            {
-                eprintln!("Error sending WS broadcast: {}", e);
-                break;
-            }
+        if let Some(text) = motd {
+            let frame = serde_json::to_string(&OutgoingMessage::Motd { text }).unwrap();
+            let _ = conn.session.text(frame).await;
         }
+        if history_limit > 0 {
+            let frame = serde_json::to_string(&OutgoingMessage::History {
+                messages: history,
+                truncated: history_truncated,
+            })
+            .unwrap();
+            let _ = match compress.then(|| deflate(frame.as_bytes())).flatten() {
+                Some(compressed) => conn.session.binary(compressed).await,
+                None => conn.session.text(frame).await,
+            };
+        }
+
+        serve_connection(conn, rx, msg_stream).await;
     });
 
-    actix_rt::spawn(async move {
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if let Ok(ws_msg) = serde_json::from_str::<WebSocketMessage>(&text) {
-                        match ws_msg.action.as_str() {
-                            "new_message" => {
-                                if let Ok(new_msg) =
-                                    serde_json::from_value::<NewMessage>(ws_msg.payload)
-                                {
-                                    match sqlx::query_as::<_, ChatMessage>(
-                                        "INSERT INTO messages (email, username, message) VALUES ($1, $2, $3) RETURNING *"
-                                    )
-                                    .bind(&email)
-                                    .bind(&username)
-                                    .bind(&new_msg.message)
-                                    .fetch_one(&db_pool)
-                                    .await {
-                                        Ok(saved_msg) => {
-                                            let _ = tx.send(OutgoingMessage::NewMessage(saved_msg));
-                                        },
-                                        Err(e) => eprintln!("Error saving message: {:?}", e),
-                                    }
-                                }
-                            }
-                            "delete_message" => {
-                                if let Ok(delete_req) =
-                                    serde_json::from_value::<DeleteMessageRequest>(ws_msg.payload)
-                                {
-                                    match sqlx::query_as::<_, ChatMessage>(
-                                        "SELECT id, email, username, message, time FROM messages WHERE id = $1"
-                                    )
-                                    .bind(delete_req.id)
-                                    .fetch_optional(&db_pool)
-                                    .await {
-                                        Ok(Some(msg)) => {
-                                            if msg.email != email {
-                                                let error_response = serde_json::json!({
-                                                    "status": "error",
-                                                    "message": "You can only delete your own messages"
-                                                });
-                                                let _ = message_session.text(serde_json::to_string(&error_response).unwrap()).await;
-                                                continue;
-                                            }
-
-                                            match sqlx::query("DELETE FROM messages WHERE id = $1")
-                                                .bind(delete_req.id)
-                                                .execute(&db_pool)
-                                                .await {
-                                                Ok(_) => {
-                                                    let broadcast = OutgoingMessage::Delete {
-                                                        message_id: delete_req.id,
-                                                    };
-                                                    let _ = tx.send(broadcast);
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("Error deleting message: {:?}", e);
-                                                }
-                                            }
-                                        },
-                                        Ok(None) => {
-                                            let error_response = serde_json::json!({
-                                                "status": "error",
-                                                "message": "Message not found"
-                                            });
-                                            let _ = message_session.text(serde_json::to_string(&error_response).unwrap()).await;
-                                        },
-                                        Err(e) => {
-                                            eprintln!("Error fetching message: {}", e);
-                                        }
-                                    }
-                                }
-                            }
-                            _ => eprintln!("Unknown action: {}", ws_msg.action),
-                        }
+    Ok(response)
+}
+
+// runs an accepted websocket to the end and releases everything it held: its
+// broadcast receiver, its session, its presence and its active-connection count
+async fn serve_connection(
+    mut conn: Connection,
+    mut rx: broadcast::Receiver<OutgoingMessage>,
+    mut msg_stream: MessageStream,
+) {
+    let outbox = conn.outbox.clone();
+    let (session, username) = (conn.session.clone(), conn.username.clone());
+    actix_rt::spawn(async move { outbox.run_writer(session, username).await });
+
+    let close_reason = run_connection(&mut conn, &mut rx, &mut msg_stream).await;
+    drop(rx);
+    conn.outbox.close();
+    if let Some((id, _)) = conn.session_slot {
+        conn.state.end_session(&conn.email, id);
+    }
+
+    METRICS.ws_connections_active.dec();
+    // a socket that closed mid-sentence never sends typing: false
+    if conn.state.stop_typing(&conn.username, conn.room_id) {
+        let _ = conn.state.tx.send(OutgoingMessage::Typing {
+            room_id: conn.room_id,
+            username: conn.username.clone(),
+            is_typing: false,
+        });
+    }
+    if conn.state.disconnect(&conn.username) {
+        conn.state.broadcast_presence();
+        conn.state.announce_leave(&conn.username);
+    }
+    conn.state.observer.on_disconnect(&conn.username);
+    record_last_seen(&conn.state.db_pool, &conn.username).await;
+    let _ = conn.session.close(close_reason).await;
+}
+
+// state of one /events stream between frames
+struct EventStream {
+    rx: broadcast::Receiver<OutgoingMessage>,
+    keepalive: tokio::time::Interval,
+    username: String,
+    room_id: i32,
+    exp: usize,
+    done: bool,
+    _slot: ConnectionSlot,
+}
+
+fn sse_frame(msg: &OutgoingMessage) -> web::Bytes {
+    web::Bytes::from(format!("data: {}\n\n", serde_json::to_string(msg).unwrap()))
+}
+
+// read-only Server-Sent Events feed of the same frames a websocket in `room`
+// would receive, for networks that block websockets
+#[get("/events")]
+pub async fn events(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<RoomQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let (claims, _) = authenticate_ws(&req, &state.db_pool).await?;
+
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "error": "shutting_down" })));
+    }
+
+    let Some(slot) = state.try_acquire_connection() else {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "error": "too_many_connections" })));
+    };
+
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room_id = require_room(&state.db_pool, room).await?;
+
+    let stream = EventStream {
+        rx: state.tx.subscribe(),
+        keepalive: interval(SSE_KEEPALIVE_INTERVAL),
+        username: claims.email,
+        room_id,
+        exp: claims.exp,
+        done: false,
+        _slot: slot,
+    };
+
+    let body = futures_util::stream::unfold(stream, |mut stream| async move {
+        if stream.done {
+            return None;
+        }
+        loop {
+            tokio::select! {
+                broadcast = stream.rx.recv() => {
+                    let msg = match broadcast {
+                        Ok(msg) => msg,
+                        Err(RecvError::Lagged(skipped)) => OutgoingMessage::resync_required(skipped),
+                        Err(RecvError::Closed) => return None,
+                    };
+                    if !is_visible_to(&msg, &stream.username, stream.room_id, None) {
+                        continue;
+                    }
+                    stream.done = matches!(msg, OutgoingMessage::ServerShutdown { .. });
+                    return Some((Ok::<_, actix_web::Error>(sse_frame(&msg.jittered())), stream));
+                }
+                _ = stream.keepalive.tick() => {
+                    if token_expired(stream.exp) {
+                        stream.done = true;
+                        return Some((Ok(sse_frame(&OutgoingMessage::TokenExpired)), stream));
                     }
+                    // comment lines keep proxies from closing an idle stream
+                    return Some((Ok(web::Bytes::from_static(b": keepalive\n\n")), stream));
                 }
-                _ => {}
             }
         }
     });
 
-    Ok(response)
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
 }
 
-#[get("/messages")]
-pub async fn get_messages(state: web::Data<Arc<AppState>>) -> impl Responder {
-    match sqlx::query_as::<_, ChatMessage>(
-        "SELECT id, email, username, message, time FROM messages ORDER BY time DESC",
+async fn record_last_seen(pool: &PgPool, username: &str) {
+    if let Err(e) = sqlx::query("UPDATE users SET last_seen = NOW() WHERE username = $1")
+        .bind(username)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!(error = %e, username, "failed to record last_seen");
+    }
+}
+
+#[get("/users/{username}/status")]
+pub async fn user_status(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    username: web::Path<String>,
+) -> Result<HttpResponse, ChatError> {
+    authenticate(&req, &state.db_pool).await?;
+
+    let username = username.into_inner();
+    let (last_seen,) = sqlx::query_as::<_, (Option<DateTime<Utc>>,)>(
+        "SELECT last_seen FROM users WHERE username = $1",
     )
-    .fetch_all(&state.db_pool)
+    .bind(&username)
+    .fetch_optional(&state.db_pool)
     .await
+    .inspect_err(|e| tracing::error!(error = %e, "failed to fetch user status"))?
+    .ok_or(ChatError::NotFound("user_not_found"))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "online": state.online.lock().unwrap().contains_key(&username),
+        "last_seen": last_seen.as_ref().map(utc::format),
+    })))
+}
+
+// usernames are only ever lowercase letters, digits, _ and -, so a prefix or
+// cursor with anything else can't match and is refused
+fn is_username_fragment(text: &str) -> bool {
+    text.len() <= 20
+        && text
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// names in alphabetical order; `after` is the last name of the previous page
+#[get("/online")]
+pub async fn get_online(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<OnlineQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ChatError::BadRequest("invalid_limit"));
+    }
+    let prefix = query.q.as_deref().unwrap_or("");
+    if !is_username_fragment(prefix) {
+        return Err(ChatError::BadRequest("invalid_query"));
+    }
+    let after = query.after.as_deref();
+    if after.is_some_and(|after| !is_username_fragment(after)) {
+        return Err(ChatError::BadRequest("invalid_cursor"));
+    }
+
+    let matching: Vec<String> = state
+        .online_users()
+        .into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    let total = matching.len();
+    let mut page: Vec<String> = matching
+        .into_iter()
+        .filter(|name| after.is_none_or(|after| name.as_str() > after))
+        .take(limit as usize + 1)
+        .collect();
+
+    let has_more = page.len() as i64 > limit;
+    page.truncate(limit as usize);
+    let next_cursor = if has_more { page.last().cloned() } else { None };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "online": page,
+        "total": total,
+        "next_cursor": next_cursor,
+    })))
+}
+
+// an RFC 3339 query parameter; `code` is the error for a malformed one
+fn parse_time(value: Option<&str>, code: &'static str) -> Result<Option<DateTime<Utc>>, ChatError> {
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| ChatError::BadRequest(code))
+        })
+        .transpose()
+}
+
+// with `user`, the results span every room unless `room` is also given, and
+// only moderators may look at someone other than themselves
+#[get("/messages")]
+pub async fn get_messages(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<MessagesQuery>,
+    validator: web::Data<RegexValidator>,
+) -> Result<HttpResponse, ChatError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let ascending = match query.order.as_deref() {
+        None | Some("desc") => false,
+        Some("asc") => true,
+        Some(_) => return Err(ChatError::BadRequest("invalid_order")),
+    };
+
+    // `before` pages newest first, `after` oldest first
+    let cursor = match (ascending, query.before.as_deref(), query.after.as_deref()) {
+        (false, cursor, None) | (true, None, cursor) => cursor
+            .map(|cursor| {
+                MessageCursor::parse(cursor).ok_or(ChatError::BadRequest("invalid_cursor"))
+            })
+            .transpose()?,
+        _ => return Err(ChatError::BadRequest("invalid_cursor")),
+    };
+
+    let since = parse_time(query.since.as_deref(), "invalid_since")?;
+    let until = parse_time(query.until.as_deref(), "invalid_until")?;
+    if let (Some(since), Some(until)) = (since, until)
+        && since > until
     {
-        Ok(messages) => HttpResponse::Ok().json(messages),
-        Err(e) => {
-            eprintln!("Error fetching messages: {}", e);
-            HttpResponse::InternalServerError().json("Error fetching messages")
+        return Err(ChatError::BadRequest("invalid_range"));
+    }
+
+    let user = query.user.as_deref();
+    if let Some(user) = user {
+        if !validator.username.is_match(user) {
+            return Err(ChatError::BadRequest("invalid_username"));
+        }
+        let claims = authenticate(&req, &state.db_pool).await?;
+        if claims.email != user && !claims.is_moderator() {
+            return Err(ChatError::Forbidden("not_moderator"));
+        }
+    }
+
+    let room = match (query.room.as_deref(), user) {
+        (Some(room), _) => Some(room),
+        (None, Some(_)) => None,
+        (None, None) => Some(DEFAULT_ROOM),
+    };
+    let room_id = match room {
+        Some(room) => Some(require_room(&state.db_pool, room).await?),
+        None => None,
+    };
+
+    // fetch one extra row to know whether another page exists
+    let mut messages = recent_messages(
+        &state.db_pool,
+        room_id,
+        user,
+        (since, until),
+        cursor,
+        ascending,
+        limit + 1,
+    )
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, ?room, ?user, "failed to fetch messages"))?;
+
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    let next_cursor = if has_more {
+        messages.last().and_then(MessageCursor::of)
+    } else {
+        None
+    };
+
+    // counts come with the page; the flag only asks for `{}` over a missing
+    // field on messages without reactions
+    if query.include_reactions {
+        for msg in messages.iter_mut() {
+            msg.reactions.get_or_insert_default();
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(MessagesPage {
+        messages,
+        next_cursor,
+    }))
+}
+
+// same rules as the new_message action; websocket and SSE listeners in the
+// room receive it like any other message
+#[post("/messages")]
+pub async fn post_messages(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<RoomQuery>,
+    new_msg: web::Json<NewMessage>,
+    limiters: web::Data<RateLimiters>,
+) -> Result<HttpResponse, ChatError> {
+    let (claims, _) = authenticate_ws(&req, &state.db_pool).await?;
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room_id = require_room(&state.db_pool, room).await?;
+
+    let posted = post_message(
+        &state,
+        &claims.sub,
+        &claims.email,
+        room_id,
+        new_msg.into_inner(),
+        || limiters.messages.check(&claims.sub).is_ok(),
+    )
+    .await
+    .inspect_err(|e| {
+        if let ChatError::Database(err) = e {
+            tracing::error!(error = %err, room, "failed to post message");
+        }
+    })?;
+
+    Ok(match posted {
+        Posted::New(msg) => HttpResponse::Created().json(msg),
+        Posted::Duplicate(msg) => HttpResponse::Ok().json(msg),
+    })
+}
+
+// public like the room history it's part of; deleted messages are a 404
+#[get("/messages/{id:\\d+}")]
+pub async fn get_message(
+    state: web::Data<Arc<AppState>>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse, ChatError> {
+    let msg = fetch_message(&state.db_pool, id.into_inner())
+        .await
+        .inspect_err(|e| {
+            if let ChatError::Database(err) = e {
+                tracing::error!(error = %err, "failed to fetch message");
+            }
+        })?;
+    Ok(HttpResponse::Ok().json(msg))
+}
+
+// results are ranked by relevance, so the cursor is an offset into the ranking
+// rather than a message id
+#[get("/messages/search")]
+pub async fn search_messages(
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(ChatError::BadRequest("empty_query"));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    // handed out as a string so clients treat it like any other cursor
+    let offset = match query.cursor.as_deref() {
+        Some(cursor) => cursor
+            .parse::<i64>()
+            .ok()
+            .filter(|&offset| offset >= 0)
+            .ok_or(ChatError::BadRequest("invalid_cursor"))?,
+        None => 0,
+    };
+
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room_id = require_room(&state.db_pool, room).await?;
+
+    let mut messages = time_query(
+        "search_messages",
+        sqlx::query_as::<_, ChatMessage>(
+            "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages
+        WHERE deleted_at IS NULL AND room_id = $2
+        AND to_tsvector('english', message) @@ plainto_tsquery('english', $1)
+        ORDER BY ts_rank(to_tsvector('english', message), plainto_tsquery('english', $1)) DESC, id DESC
+        LIMIT $3 OFFSET $4",
+        )
+        .bind(q)
+        .bind(room_id)
+        .bind(limit + 1)
+        .bind(offset)
+        .fetch_all(&state.db_pool),
+    )
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, query = q, room, "failed to search messages"))?;
+
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    let next_cursor = if has_more {
+        Some((offset + messages.len() as i64).to_string())
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(MessagesPage {
+        messages,
+        next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::FromRequest;
+    use std::io::Read;
+
+    fn message_by(email: &str) -> ChatMessage {
+        ChatMessage {
+            id: Some(1),
+            email: email.to_string(),
+            username: email.split('@').next().unwrap().to_string(),
+            message: "hello".to_string(),
+            time: Utc::now(),
+            edited_at: None,
+            room_id: 1,
+            attachment_url: None,
+            parent_id: None,
+            nonce: None,
+            reactions: None,
+        }
+    }
+
+    #[test]
+    fn author_and_moderators_can_delete() {
+        let msg = message_by("alice@example.com");
+        assert!(can_delete(&msg, "alice@example.com", false));
+        assert!(can_delete(&msg, "mod@example.com", true));
+    }
+
+    #[test]
+    fn others_cannot_delete() {
+        let msg = message_by("alice@example.com");
+        assert!(!can_delete(&msg, "bob@example.com", false));
+    }
+
+    // never touches the database; connect_lazy only needs a runtime to exist
+    fn test_state(sanitize_mode: SanitizeMode) -> AppState {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy(&config::test().database_url)
+            .unwrap();
+        let mut state = AppState::new(pool, broadcast::channel(16).0, None);
+        state.sanitize_mode = sanitize_mode;
+        state
+    }
+
+    #[tokio::test]
+    async fn escape_mode_neutralizes_script_payloads() {
+        let state = test_state(SanitizeMode::Escape);
+        let clean = prepare_message(
+            &state,
+            r#"<script>alert(1)</script><img src=x onerror="alert(2)">"#,
+        )
+        .unwrap();
+        assert!(!clean.contains('<') && !clean.contains('>') && !clean.contains('"'));
+        assert_eq!(
+            clean,
+            "&lt;script&gt;alert(1)&lt;/script&gt;&lt;img src=x onerror=&quot;alert(2)&quot;&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn allowlist_mode_strips_script_payloads() {
+        let state = test_state(SanitizeMode::Allowlist);
+        let clean = prepare_message(
+            &state,
+            r#"<b>hi</b><script>alert(1)</script><img src=x onerror="alert(2)">"#,
+        )
+        .unwrap();
+        assert!(clean.starts_with("<b>hi</b>"));
+        assert!(!clean.contains("<script"));
+        assert!(!clean.contains("onerror"));
+    }
+
+    #[tokio::test]
+    async fn payload_that_sanitizes_to_nothing_is_rejected() {
+        let state = test_state(SanitizeMode::Allowlist);
+        assert_eq!(
+            prepare_message(&state, "<script>alert(1)</script>"),
+            Err("empty_message")
+        );
+    }
+
+    #[test]
+    fn delete_reaches_its_own_room_only() {
+        let delete = OutgoingMessage::Delete {
+            room_id: 1,
+            message_id: 7,
+        };
+        assert!(is_visible_to(&delete, "alice", 1, None));
+        assert!(!is_visible_to(&delete, "alice", 2, None));
+    }
+
+    // what run_connection would forward to a socket in `room_id` from
+    // everything broadcast so far
+    fn delivered(
+        rx: &mut broadcast::Receiver<OutgoingMessage>,
+        username: &str,
+        room_id: i32,
+    ) -> Vec<OutgoingMessage> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| is_visible_to(msg, username, room_id, None))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn broadcast_delete_is_delivered_to_its_room_only() {
+        let state = test_state(SanitizeMode::Escape);
+        let mut in_room_1 = state.tx.subscribe();
+        let mut in_room_2 = state.tx.subscribe();
+
+        state
+            .tx
+            .send(OutgoingMessage::Delete {
+                room_id: 1,
+                message_id: 7,
+            })
+            .unwrap();
+
+        let frames = delivered(&mut in_room_1, "alice", 1);
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(
+            frames[0],
+            OutgoingMessage::Delete {
+                room_id: 1,
+                message_id: 7
+            }
+        ));
+        assert!(delivered(&mut in_room_2, "bob", 2).is_empty());
+    }
+
+    #[test]
+    fn edit_reaches_its_own_room_only() {
+        let edit = OutgoingMessage::Edit {
+            room_id: 1,
+            message_id: 7,
+            message: "fixed".to_string(),
+            edited_at: Utc::now(),
+        };
+        assert!(is_visible_to(&edit, "alice", 1, None));
+        assert!(!is_visible_to(&edit, "alice", 2, None));
+    }
+
+    // a deterministic stand-in for a busy room: six people trading short
+    // messages built from a small vocabulary, 37 seconds apart
+    fn sample_history(count: usize) -> String {
+        const USERS: [&str; 6] = ["alice", "bob", "carol", "dave", "erin", "frank"];
+        const WORDS: [&str; 48] = [
+            "hey", "anyone", "around", "the", "deploy", "is", "done", "looks", "good", "to", "me",
+            "can", "you", "check", "logs", "again", "thanks", "lunch", "later", "meeting", "moved",
+            "tomorrow", "lol", "nice", "why", "did", "build", "fail", "on", "main", "branch", "I",
+            "think", "it", "was", "flaky", "test", "retrying", "now", "coffee", "break", "back",
+            "in", "ten", "minutes", "pushed", "fix", "review",
+        ];
+        let mut seed: u32 = 42;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as usize
+        };
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let messages = (0..count)
+            .map(|i| {
+                let user = USERS[next() % USERS.len()];
+                let words = 3 + next() % 12;
+                let message = (0..words)
+                    .map(|_| WORDS[next() % WORDS.len()])
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                ChatMessage {
+                    id: Some(i as i32 + 1),
+                    email: format!("{}@example.com", user),
+                    username: user.to_string(),
+                    message,
+                    time: start + chrono::Duration::seconds(37 * i as i64),
+                    edited_at: None,
+                    room_id: 1,
+                    attachment_url: None,
+                    parent_id: None,
+                    nonce: None,
+                    reactions: None,
+                }
+            })
+            .collect();
+        serde_json::to_string(&OutgoingMessage::History {
+            messages,
+            truncated: false,
+        })
+        .unwrap()
+    }
+
+    // the README quotes these sizes next to `?compress=true`; a 500-message
+    // frame of about 107 KB deflates to about 12 KB
+    #[test]
+    fn history_frame_compresses_well() {
+        let frame = sample_history(500);
+        let compressed = deflate(frame.as_bytes()).unwrap();
+        assert!(frame.len() > 100_000);
+        assert!(
+            compressed.len() * 5 < frame.len(),
+            "{} bytes deflated to {}",
+            frame.len(),
+            compressed.len()
+        );
+
+        let mut inflated = String::new();
+        flate2::read::ZlibDecoder::new(compressed.as_slice())
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, frame);
+    }
+
+    // a socket whose client never sends a frame. the response is handed back
+    // so the session's outgoing channel stays open for pings
+    async fn silent_socket() -> (HttpResponse, Session, MessageStream) {
+        let req = actix_web::test::TestRequest::get()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_request();
+        let mut silence: actix_web::dev::Payload = actix_web::dev::Payload::Stream {
+            payload: Box::pin(futures_util::stream::pending::<
+                Result<web::Bytes, actix_web::error::PayloadError>,
+            >()),
+        };
+        let body = web::Payload::from_request(&req, &mut silence)
+            .await
+            .unwrap();
+        actix_ws::handle(&req, body).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn silent_client_is_disconnected_and_released() {
+        time::pause();
+        let state = Arc::new(test_state(SanitizeMode::Escape));
+        let (_response, session, msg_stream) = silent_socket().await;
+        let conn = Connection {
+            email: "alice@example.com".to_string(),
+            username: "alice".to_string(),
+            is_moderator: false,
+            room_id: 1,
+            state: state.clone(),
+            session,
+            last_typing: None,
+            message_limiter: TokenBucket::new(),
+            history_until: None,
+            exp: (Utc::now().timestamp() + 3600) as usize,
+            ip: "127.0.0.1".to_string(),
+            session_slot: None,
+            last_seen_written: None,
+            outbox: Arc::new(Outbox::default()),
+            _slot: state.try_acquire_connection().unwrap(),
+        };
+        let rx = state.tx.subscribe();
+        assert_eq!(state.tx.receiver_count(), 1);
+        // as ws_handler does once the socket is accepted
+        METRICS.ws_connections_active.inc();
+        let active = METRICS.ws_connections_active.get();
+
+        let started = time::Instant::now();
+        serve_connection(conn, rx, msg_stream).await;
+        assert!(started.elapsed() > CLIENT_TIMEOUT);
+        assert_eq!(state.tx.receiver_count(), 0);
+        assert_eq!(METRICS.ws_connections_active.get(), active - 1);
+    }
+
+    #[test]
+    fn client_times_out_after_a_silent_timeout() {
+        let last_heartbeat = time::Instant::now();
+        assert!(!client_timed_out(
+            last_heartbeat,
+            last_heartbeat + CLIENT_TIMEOUT
+        ));
+        assert!(client_timed_out(
+            last_heartbeat,
+            last_heartbeat + CLIENT_TIMEOUT + HEARTBEAT_INTERVAL
+        ));
+    }
+
+    #[test]
+    fn cursor_parses_time_and_id() {
+        let cursor = MessageCursor::parse("1700000000123456_42").unwrap();
+        assert_eq!(cursor.id, 42);
+        assert_eq!(
+            cursor.time,
+            DateTime::from_timestamp_micros(1_700_000_000_123_456)
+        );
+        assert_eq!(serde_json::to_value(cursor).unwrap(), "1700000000123456_42");
+    }
+
+    #[test]
+    fn cursor_accepts_legacy_bare_id() {
+        let cursor = MessageCursor::parse("42").unwrap();
+        assert_eq!(cursor.id, 42);
+        assert!(cursor.time.is_none());
+        assert_eq!(serde_json::to_value(cursor).unwrap(), "42");
+    }
+
+    #[test]
+    fn cursor_rejects_garbage() {
+        assert!(MessageCursor::parse("").is_none());
+        assert!(MessageCursor::parse("abc").is_none());
+        assert!(MessageCursor::parse("123_").is_none());
+        assert!(MessageCursor::parse("_42").is_none());
+        assert!(MessageCursor::parse("x_42").is_none());
+    }
+
+    // every message id in the room, three at a time, following next_cursor
+    async fn walk_pages(pool: &PgPool, room_id: i32, ascending: bool) -> Vec<i32> {
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = recent_messages(
+                pool,
+                Some(room_id),
+                None,
+                (None, None),
+                cursor,
+                ascending,
+                3,
+            )
+            .await
+            .unwrap();
+            seen.extend(page.iter().filter_map(|msg| msg.id));
+            // round trip through the wire format, as a client would
+            cursor = match page.last().and_then(MessageCursor::of) {
+                Some(cursor) => {
+                    let wire = serde_json::to_value(cursor).unwrap();
+                    MessageCursor::parse(wire.as_str().unwrap())
+                }
+                None => return seen,
+            };
+        }
+    }
+
+    #[sqlx::test]
+    async fn pages_split_rows_sharing_a_timestamp(pool: PgPool) {
+        sqlx::query("INSERT INTO users (username, email, password) VALUES ('alice', 'alice@example.com', 'x')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let room_id = require_room(&pool, DEFAULT_ROOM).await.unwrap();
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        for i in 0..7 {
+            sqlx::query(
+                "INSERT INTO messages (email, username, message, time, room_id)
+                VALUES ('alice@example.com', 'alice', $1, $2, $3)",
+            )
+            .bind(format!("message {}", i))
+            .bind(time)
+            .bind(room_id)
+            .execute(&pool)
+            .await
+            .unwrap();
         }
+
+        let newest_first = walk_pages(&pool, room_id, false).await;
+        let mut expected = newest_first.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        expected.dedup();
+        assert_eq!(newest_first.len(), 7);
+        assert_eq!(newest_first, expected);
+
+        let oldest_first = walk_pages(&pool, room_id, true).await;
+        expected.reverse();
+        assert_eq!(oldest_first, expected);
     }
 }
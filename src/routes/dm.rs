@@ -0,0 +1,122 @@
+// libs
+use crate::middlewares::authenticate;
+use crate::routes::chat::{ChatError, MessagesPage};
+use crate::utc;
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+// structs
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DirectMessage {
+    pub id: i32,
+    pub sender: String,
+    pub recipient: String,
+    pub message: String,
+    #[serde(serialize_with = "utc::serialize")]
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendDmRequest {
+    pub username: String,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DmHistoryQuery {
+    pub limit: Option<i64>,
+    pub before: Option<i32>,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+// mods
+// returns None when the recipient doesn't exist
+pub async fn save_direct_message(
+    pool: &PgPool,
+    sender_email: &str,
+    sender_username: &str,
+    dm_req: SendDmRequest,
+) -> Result<Option<DirectMessage>, sqlx::Error> {
+    let recipient_email =
+        match sqlx::query_scalar::<_, String>("SELECT email FROM users WHERE username = $1")
+            .bind(&dm_req.username)
+            .fetch_optional(pool)
+            .await?
+        {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+
+    let (id, time) = sqlx::query_as::<_, (i32, DateTime<Utc>)>(
+        "INSERT INTO direct_messages (sender_email, recipient_email, message)
+        VALUES ($1, $2, $3) RETURNING id, time",
+    )
+    .bind(sender_email)
+    .bind(&recipient_email)
+    .bind(&dm_req.message)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Some(DirectMessage {
+        id,
+        sender: sender_username.to_string(),
+        recipient: dm_req.username,
+        message: dm_req.message,
+        time,
+    }))
+}
+
+// routes
+#[get("/dm/{username}")]
+pub async fn get_direct_messages(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+    query: web::Query<DmHistoryQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = authenticate(&req, pool.get_ref()).await?;
+    let other = path.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // only conversations the caller takes part in can match
+    let mut messages = sqlx::query_as::<_, DirectMessage>(
+        "SELECT dm.id, s.username AS sender, r.username AS recipient, dm.message, dm.time
+        FROM direct_messages dm
+        JOIN users s ON s.email = dm.sender_email
+        JOIN users r ON r.email = dm.recipient_email
+        WHERE ((dm.sender_email = $1 AND r.username = $2)
+            OR (dm.recipient_email = $1 AND s.username = $2))
+        AND ($3::INT IS NULL OR dm.id < $3)
+        ORDER BY dm.id DESC
+        LIMIT $4",
+    )
+    .bind(&claims.sub)
+    .bind(&other)
+    .bind(query.before)
+    .bind(limit + 1)
+    .fetch_all(pool.get_ref())
+    .await
+    .inspect_err(
+        |e| tracing::error!(error = %e, with = %other, "failed to fetch direct messages"),
+    )?;
+
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    let next_cursor = if has_more {
+        messages.last().map(|m| m.id)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(MessagesPage {
+        messages,
+        next_cursor,
+    }))
+}
@@ -0,0 +1,210 @@
+// libs
+use crate::middlewares::{CONFIRM_PASSWORD_HEADER, RateLimiters, authenticate};
+use crate::routes::chat::{ChatError, ChatMessage};
+use crate::routes::dm::DirectMessage;
+use crate::routes::metrics::time_query;
+use crate::utc;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use bcrypt::verify;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::{FromRow, PgPool};
+
+// structs
+#[derive(Debug, Serialize, FromRow)]
+struct ExportProfile {
+    email: String,
+    username: String,
+    role: String,
+    verified: bool,
+    #[serde(serialize_with = "utc::serialize")]
+    created_at: DateTime<Utc>,
+    #[serde(serialize_with = "utc::option::serialize")]
+    last_seen: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    password: String,
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+    Messages,
+    DirectMessages,
+    Done,
+}
+
+// state of one export download between chunks. rows are read in id order a
+// batch at a time, so memory use doesn't grow with the size of the history
+struct ExportStream {
+    pool: PgPool,
+    email: String,
+    section: Section,
+    after: i32,
+    first: bool,
+}
+
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+// mods
+impl ExportStream {
+    // appends one batch of `section` to `chunk`; true once the section is exhausted
+    async fn write_batch<T>(
+        &mut self,
+        chunk: &mut Vec<u8>,
+        operation: &'static str,
+        sql: &'static str,
+        id_of: fn(&T) -> i32,
+    ) -> Result<bool, sqlx::Error>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Serialize + Send + Unpin,
+    {
+        let rows = time_query(
+            operation,
+            sqlx::query_as::<_, T>(sql)
+                .bind(&self.email)
+                .bind(self.after)
+                .bind(EXPORT_BATCH_SIZE)
+                .fetch_all(&self.pool),
+        )
+        .await?;
+
+        for row in &rows {
+            if !self.first {
+                chunk.push(b',');
+            }
+            self.first = false;
+            serde_json::to_writer(&mut *chunk, row).unwrap();
+            self.after = id_of(row);
+        }
+        Ok((rows.len() as i64) < EXPORT_BATCH_SIZE)
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<web::Bytes>, sqlx::Error> {
+        let mut chunk = Vec::new();
+        match self.section {
+            Section::Messages => {
+                let done = self
+                    .write_batch::<ChatMessage>(
+                        &mut chunk,
+                        "export_messages",
+                        "SELECT id, email, username, message, time, edited_at, room_id, attachment_url, parent_id, nonce FROM messages
+                        WHERE email = $1 AND deleted_at IS NULL AND id > $2
+                        ORDER BY id
+                        LIMIT $3",
+                        |msg| msg.id.unwrap_or_default(),
+                    )
+                    .await?;
+                if done {
+                    chunk.extend_from_slice(br#"],"direct_messages":["#);
+                    self.section = Section::DirectMessages;
+                    self.after = 0;
+                    self.first = true;
+                }
+            }
+            Section::DirectMessages => {
+                let done = self
+                    .write_batch::<DirectMessage>(
+                        &mut chunk,
+                        "export_direct_messages",
+                        "SELECT dm.id, s.username AS sender, r.username AS recipient, dm.message, dm.time
+                        FROM direct_messages dm
+                        JOIN users s ON s.email = dm.sender_email
+                        JOIN users r ON r.email = dm.recipient_email
+                        WHERE (dm.sender_email = $1 OR dm.recipient_email = $1) AND dm.id > $2
+                        ORDER BY dm.id
+                        LIMIT $3",
+                        |dm| dm.id,
+                    )
+                    .await?;
+                if done {
+                    chunk.extend_from_slice(b"]}");
+                    self.section = Section::Done;
+                }
+            }
+            Section::Done => return Ok(None),
+        }
+        Ok(Some(web::Bytes::from(chunk)))
+    }
+}
+
+// routes
+// the whole account as one json document, streamed. the password is asked
+// again since a stolen session shouldn't be enough to walk off with everything
+#[get("/account/export")]
+pub async fn export_account(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    limiters: web::Data<RateLimiters>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = authenticate(&req, pool.get_ref()).await?;
+
+    // the password check shares the login lockout, so a stolen session can't
+    // be used to guess the password here instead
+    if let Err(retry_after) = limiters.login.check(&claims.sub) {
+        let retry_after = retry_after.as_secs().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(serde_json::json!({
+                "error": "account_locked",
+                "retry_after": retry_after,
+            })));
+    }
+
+    let password = req
+        .headers()
+        .get(CONFIRM_PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(ChatError::BadRequest("password_required"))?;
+
+    let profile = sqlx::query_as::<_, ExportProfile>(
+        "SELECT email, username, role, verified, created_at, last_seen, password FROM users WHERE email = $1",
+    )
+    .bind(&claims.sub)
+    .fetch_optional(pool.get_ref())
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, "failed to fetch profile for export"))?
+    .ok_or(ChatError::NotFound("user_not_found"))?;
+
+    if !verify(password, &profile.password).unwrap_or(false) {
+        limiters.login.record_failure(&claims.sub);
+        return Err(ChatError::Forbidden("invalid_password"));
+    }
+    limiters.login.reset(&claims.sub);
+
+    let mut head = br#"{"profile":"#.to_vec();
+    serde_json::to_writer(&mut head, &profile).unwrap();
+    head.extend_from_slice(br#","messages":["#);
+
+    let export = ExportStream {
+        pool: pool.get_ref().clone(),
+        email: claims.sub,
+        section: Section::Messages,
+        after: 0,
+        first: true,
+    };
+    let rows = futures_util::stream::unfold(export, |mut export| async move {
+        match export.next_chunk().await {
+            Ok(chunk) => chunk.map(|chunk| (Ok(chunk), export)),
+            // headers are already out, so all that's left is cutting the body short
+            Err(e) => {
+                tracing::error!(error = %e, email = %export.email, "account export failed");
+                export.section = Section::Done;
+                Some((Err(actix_web::error::ErrorInternalServerError(e)), export))
+            }
+        }
+    });
+    let head = futures_util::stream::once(async move { Ok(web::Bytes::from(head)) });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"kutter-export-{}.json\"",
+                profile.username
+            ),
+        ))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(futures_util::StreamExt::chain(head, rows)))
+}
@@ -0,0 +1,73 @@
+// libs
+use actix_web::{HttpResponse, Responder, get, web};
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+
+// routes
+#[get("/health")]
+pub async fn health_check(pool: web::Data<PgPool>) -> impl Responder {
+    let query = sqlx::query("SELECT 1").execute(pool.get_ref());
+    let healthy = matches!(
+        tokio::time::timeout(Duration::from_secs(2), query).await,
+        Ok(Ok(_))
+    );
+
+    let size = pool.size();
+    let idle = pool.num_idle() as u32;
+    let body = json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "pool": {
+            "size": size,
+            "idle": idle,
+            "active": size.saturating_sub(idle),
+        }
+    });
+
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{App, test};
+    use sqlx::postgres::PgPoolOptions;
+
+    async fn check(pool: PgPool) -> (StatusCode, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .service(health_check),
+        )
+        .await;
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        let status = res.status();
+        (status, test::read_body_json(res).await)
+    }
+
+    #[sqlx::test]
+    async fn reachable_database_is_ok(pool: PgPool) {
+        let (status, body) = check(pool).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[actix_web::test]
+    async fn unreachable_database_is_unavailable() {
+        // nothing listens on port 1, so every connection attempt fails fast
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_lazy("postgres://kutter@127.0.0.1:1/kutter")
+            .unwrap();
+
+        let (status, body) = check(pool).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "degraded");
+    }
+}
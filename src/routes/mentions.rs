@@ -0,0 +1,61 @@
+// libs
+use crate::routes::chat::{ChatMessage, OutgoingMessage};
+use regex::Regex;
+use sqlx::PgPool;
+use std::sync::LazyLock;
+
+// same charset as RegexValidator::username; the leading group keeps emails
+// like bob@example.com from reading as a mention
+static MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)(?:^|[^\w@.])@([a-z0-9_-]{2,20})").unwrap());
+
+// mods
+// distinct lowercase usernames mentioned in `message`, minus the author
+pub fn parse_mentions(message: &str, author: &str) -> Vec<String> {
+    let mut usernames: Vec<String> = MENTION
+        .captures_iter(message)
+        .map(|caps| caps[1].to_lowercase())
+        .filter(|username| username != author)
+        .collect();
+    usernames.sort();
+    usernames.dedup();
+    usernames
+}
+
+// stores mentions of users that exist and returns the frame announcing them,
+// or None when nobody real was mentioned
+pub async fn record_mentions(
+    pool: &PgPool,
+    msg: &ChatMessage,
+) -> Result<Option<OutgoingMessage>, sqlx::Error> {
+    let (Some(message_id), usernames) = (msg.id, parse_mentions(&msg.message, &msg.username))
+    else {
+        return Ok(None);
+    };
+    if usernames.is_empty() {
+        return Ok(None);
+    }
+
+    let mentioned = sqlx::query_scalar::<_, String>(
+        "WITH found AS (SELECT email, username FROM users WHERE username = ANY($2)),
+        inserted AS (
+            INSERT INTO message_mentions (message_id, email)
+            SELECT $1, email FROM found
+            ON CONFLICT DO NOTHING
+        )
+        SELECT username FROM found ORDER BY username",
+    )
+    .bind(message_id)
+    .bind(&usernames)
+    .fetch_all(pool)
+    .await?;
+
+    if mentioned.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(OutgoingMessage::Mention {
+        room_id: msg.room_id,
+        message_id,
+        mentioned,
+    }))
+}
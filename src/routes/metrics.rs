@@ -0,0 +1,183 @@
+// libs
+use crate::config;
+use crate::middlewares::{client_ip, constant_time_eq};
+use actix_web::{HttpRequest, HttpResponse, Responder, get, http::header};
+use std::fmt::Write;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tracing::Instrument;
+
+// structs
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// upper bounds in seconds; the implicit last bucket is +Inf
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, started: Instant) {
+        let elapsed = started.elapsed();
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    pub ws_connections_total: Counter,
+    pub ws_connections_active: Counter,
+    pub messages_sent_total: Counter,
+    pub messages_deleted_total: Counter,
+    pub auth_failures_total: Counter,
+    pub db_query_duration: Histogram,
+}
+
+pub static METRICS: Metrics = Metrics {
+    ws_connections_total: Counter::new(),
+    ws_connections_active: Counter::new(),
+    messages_sent_total: Counter::new(),
+    messages_deleted_total: Counter::new(),
+    auth_failures_total: Counter::new(),
+    db_query_duration: Histogram::new(),
+};
+
+// mods
+// awaits a database future and records how long it took. the query runs in a
+// span named after `operation`, so sqlx's slow-statement warning, which is
+// logged from inside it, says which operation was slow
+pub async fn time_query<F: Future>(operation: &'static str, query: F) -> F::Output {
+    let started = Instant::now();
+    let output = query
+        .instrument(tracing::warn_span!("query", operation))
+        .await;
+    METRICS.db_query_duration.observe(started);
+    output
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "kutter_ws_connections_total",
+        "counter",
+        "Websocket connections accepted.",
+        METRICS.ws_connections_total.get(),
+    );
+    write_metric(
+        &mut out,
+        "kutter_ws_connections_active",
+        "gauge",
+        "Websocket connections currently open.",
+        METRICS.ws_connections_active.get(),
+    );
+    write_metric(
+        &mut out,
+        "kutter_messages_sent_total",
+        "counter",
+        "Room messages stored and broadcast.",
+        METRICS.messages_sent_total.get(),
+    );
+    write_metric(
+        &mut out,
+        "kutter_messages_deleted_total",
+        "counter",
+        "Room messages deleted.",
+        METRICS.messages_deleted_total.get(),
+    );
+    write_metric(
+        &mut out,
+        "kutter_auth_failures_total",
+        "counter",
+        "Failed login attempts.",
+        METRICS.auth_failures_total.get(),
+    );
+
+    let name = "kutter_db_query_duration_seconds";
+    let histogram = &METRICS.db_query_duration;
+    let _ = writeln!(out, "# HELP {} Database query latency.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (i, bucket) in histogram.buckets.iter().enumerate() {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let bound = LATENCY_BUCKETS
+            .get(i)
+            .map_or("+Inf".to_string(), |bound| bound.to_string());
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, cumulative);
+    out
+}
+
+// with METRICS_TOKEN set, scrapers must send it as a bearer token; without it
+// only loopback clients may scrape
+fn scrape_allowed(req: &HttpRequest) -> bool {
+    match &config::get().metrics_token {
+        Some(token) => req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())),
+        _ => client_ip(req)
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+    }
+}
+
+// routes
+#[get("/metrics")]
+pub async fn metrics(req: HttpRequest) -> impl Responder {
+    if !scrape_allowed(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
@@ -1,4 +1,75 @@
+// libs
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
 // mods
+pub mod admin;
 pub mod auth;
 pub mod chat;
+pub mod dm;
+pub mod export;
 pub mod friend;
+pub mod health;
+pub mod mentions;
+pub mod metrics;
+pub mod reactions;
+pub mod receipts;
+pub mod uploads;
+
+// json, auth and websocket routes live under this; breaking changes get a new
+// version next to it
+pub const API_PREFIX: &str = "/api/v1";
+
+// methods each route under API_PREFIX answers, by the pattern it is declared
+// with. actix can tell that a path exists but not which methods it takes, so
+// this has to be kept in step with the handlers' attributes
+const API_METHODS: &[(&str, &[&str])] = &[
+    ("/register", &["POST"]),
+    ("/login", &["POST"]),
+    ("/auth/username-available", &["GET"]),
+    ("/auth/password-policy", &["GET"]),
+    ("/auth/resend-verification", &["POST"]),
+    ("/verify", &["GET"]),
+    ("/verify_email", &["POST"]),
+    ("/me", &["GET"]),
+    ("/refresh", &["POST"]),
+    ("/logout", &["DELETE"]),
+    ("/logout_all", &["POST"]),
+    ("/password/forgot", &["POST"]),
+    ("/password/reset", &["POST"]),
+    ("/auth/change-password", &["POST"]),
+    ("/account", &["DELETE"]),
+    ("/account/username", &["PUT"]),
+    ("/account/export", &["GET"]),
+    ("/ws", &["GET"]),
+    ("/events", &["GET"]),
+    ("/messages", &["GET", "POST"]),
+    ("/messages/search", &["GET"]),
+    ("/messages/{id:\\d+}", &["GET"]),
+    ("/online", &["GET"]),
+    ("/users/{username}/status", &["GET"]),
+    ("/dm/{username}", &["GET"]),
+    ("/upload", &["POST"]),
+    ("/unread-count", &["GET"]),
+    ("/admin/users", &["GET"]),
+    ("/admin/users/{username}/ban", &["POST"]),
+    ("/admin/audit", &["GET"]),
+    ("/admin/motd", &["PUT"]),
+    ("/admin/invites", &["POST"]),
+];
+
+// the api scope's default service: a path that exists was asked for with a
+// method it doesn't take, anything else is unknown. both answer in json, so
+// api clients never get the spa's index.html
+pub async fn api_fallback(req: HttpRequest) -> HttpResponse {
+    let Some(pattern) = req.match_pattern() else {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": "not_found" }));
+    };
+
+    let route = pattern.strip_prefix(API_PREFIX).unwrap_or(&pattern);
+    let mut response = HttpResponse::MethodNotAllowed();
+    if let Some((_, methods)) = API_METHODS.iter().find(|(path, _)| *path == route) {
+        response.insert_header((header::ALLOW, methods.join(", ")));
+    }
+    response.json(serde_json::json!({ "error": "method_not_allowed" }))
+}
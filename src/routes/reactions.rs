@@ -0,0 +1,244 @@
+// libs
+use crate::routes::chat::OutgoingMessage;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+// structs
+#[derive(Debug, Deserialize)]
+pub struct ReactionRequest {
+    pub message_id: i32,
+    pub emoji: String,
+}
+
+const MAX_EMOJI_LEN: usize = 32;
+
+const ZWJ: char = '\u{200D}';
+const VARIATION_SELECTOR: char = '\u{FE0F}';
+const KEYCAP: char = '\u{20E3}';
+
+// mods
+// a single emoji: one pictograph, optionally with a presentation selector, a
+// skin tone and (for subdivision flags) tag characters, several of those
+// joined with ZWJ, a country flag, or a keycap. plain text like "lol" or
+// "<b>" is refused
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN {
+        return false;
+    }
+    is_flag(emoji) || is_keycap(emoji) || emoji.split(ZWJ).all(is_pictograph_sequence)
+}
+
+fn is_pictographic(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x00A9
+            | 0x00AE
+            | 0x203C
+            | 0x2049
+            | 0x2122
+            | 0x2139
+            | 0x2194..=0x2199
+            | 0x21A9..=0x21AA
+            | 0x231A..=0x231B
+            | 0x2328
+            | 0x23CF
+            | 0x23E9..=0x23F3
+            | 0x23F8..=0x23FA
+            | 0x24C2
+            | 0x25AA..=0x25AB
+            | 0x25B6
+            | 0x25C0
+            | 0x25FB..=0x25FE
+            | 0x2600..=0x27BF
+            | 0x2934..=0x2935
+            | 0x2B05..=0x2B07
+            | 0x2B1B..=0x2B1C
+            | 0x2B50
+            | 0x2B55
+            | 0x3030
+            | 0x303D
+            | 0x3297
+            | 0x3299
+            | 0x1F000..=0x1F1E5
+            | 0x1F200..=0x1F3FA
+            | 0x1F400..=0x1FAFF
+    )
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1F1E6..=0x1F1FF)
+}
+
+fn is_skin_tone(c: char) -> bool {
+    matches!(c as u32, 0x1F3FB..=0x1F3FF)
+}
+
+fn is_tag(c: char) -> bool {
+    matches!(c as u32, 0xE0020..=0xE007E)
+}
+
+fn is_flag(emoji: &str) -> bool {
+    let mut chars = emoji.chars();
+    matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(a), Some(b), None) if is_regional_indicator(a) && is_regional_indicator(b)
+    )
+}
+
+// 1️⃣, #️⃣ and friends; the selector is optional in the wild
+fn is_keycap(emoji: &str) -> bool {
+    let mut chars = emoji.chars().peekable();
+    if !chars
+        .next()
+        .is_some_and(|c| c.is_ascii_digit() || c == '#' || c == '*')
+    {
+        return false;
+    }
+    chars.next_if_eq(&VARIATION_SELECTOR);
+    chars.next() == Some(KEYCAP) && chars.next().is_none()
+}
+
+// one part of a ZWJ sequence
+fn is_pictograph_sequence(part: &str) -> bool {
+    let mut chars = part.chars().peekable();
+    if !chars.next().is_some_and(is_pictographic) {
+        return false;
+    }
+    chars.next_if_eq(&VARIATION_SELECTOR);
+    chars.next_if(|&c| is_skin_tone(c));
+    // tags only ever end in the cancel tag, e.g. the Scotland flag
+    if chars.next_if(|&c| is_tag(c)).is_some() {
+        while chars.next_if(|&c| is_tag(c)).is_some() {}
+        return chars.next() == Some('\u{E007F}') && chars.next().is_none();
+    }
+    chars.next().is_none()
+}
+
+// None when the message doesn't exist or was deleted
+async fn message_room(pool: &PgPool, message_id: i32) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>(
+        "SELECT room_id FROM messages WHERE id = $1 AND deleted_at IS NULL",
+    )
+    .bind(message_id)
+    .fetch_optional(pool)
+    .await
+}
+
+// current state of one emoji on one message, as broadcast to clients
+async fn reaction_update(
+    pool: &PgPool,
+    room_id: i32,
+    message_id: i32,
+    emoji: String,
+) -> Result<OutgoingMessage, sqlx::Error> {
+    let reacted_by = sqlx::query_scalar::<_, String>(
+        "SELECT u.username FROM message_reactions mr
+        JOIN users u ON u.email = mr.email
+        WHERE mr.message_id = $1 AND mr.emoji = $2
+        ORDER BY mr.created_at",
+    )
+    .bind(message_id)
+    .bind(&emoji)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(OutgoingMessage::Reaction {
+        room_id,
+        message_id,
+        emoji,
+        count: reacted_by.len() as i64,
+        reacted_by,
+    })
+}
+
+// adding the same emoji twice is a no-op thanks to the primary key
+pub async fn add_reaction(
+    pool: &PgPool,
+    email: &str,
+    req: ReactionRequest,
+) -> Result<Option<OutgoingMessage>, sqlx::Error> {
+    let Some(room_id) = message_room(pool, req.message_id).await? else {
+        return Ok(None);
+    };
+
+    sqlx::query(
+        "INSERT INTO message_reactions (message_id, email, emoji) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(req.message_id)
+    .bind(email)
+    .bind(&req.emoji)
+    .execute(pool)
+    .await?;
+
+    reaction_update(pool, room_id, req.message_id, req.emoji)
+        .await
+        .map(Some)
+}
+
+pub async fn remove_reaction(
+    pool: &PgPool,
+    email: &str,
+    req: ReactionRequest,
+) -> Result<Option<OutgoingMessage>, sqlx::Error> {
+    let Some(room_id) = message_room(pool, req.message_id).await? else {
+        return Ok(None);
+    };
+
+    sqlx::query(
+        "DELETE FROM message_reactions WHERE message_id = $1 AND email = $2 AND emoji = $3",
+    )
+    .bind(req.message_id)
+    .bind(email)
+    .bind(&req.emoji)
+    .execute(pool)
+    .await?;
+
+    reaction_update(pool, room_id, req.message_id, req.emoji)
+        .await
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_emoji() {
+        for emoji in [
+            "👍",
+            "❤️",
+            "⭐",
+            "👍🏽",
+            "👩‍💻",
+            "👨‍👩‍👧‍👦",
+            "🏳️‍🌈",
+            "🇩🇪",
+            "1️⃣",
+            "#⃣",
+            "🏴\u{E0067}\u{E0062}\u{E0073}\u{E0063}\u{E0074}\u{E007F}",
+        ] {
+            assert!(is_valid_emoji(emoji), "{:?} should be accepted", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_text() {
+        for text in [
+            "",
+            "lol",
+            "<b>",
+            "a",
+            "1",
+            "👍 👍",
+            "👍👍",
+            "👍lol",
+            "🇩",
+            "🇩🇪🇫🇷",
+            "\u{200D}",
+            "🏽",
+        ] {
+            assert!(!is_valid_emoji(text), "{:?} should be rejected", text);
+        }
+    }
+}
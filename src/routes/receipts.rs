@@ -0,0 +1,83 @@
+// libs
+use crate::middlewares::authenticate;
+use crate::routes::chat::{AppState, ChatError, DEFAULT_ROOM, OutgoingMessage, require_room};
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+// structs
+#[derive(Debug, Deserialize)]
+pub struct MarkReadRequest {
+    pub message_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnreadQuery {
+    pub room: Option<String>,
+}
+
+// mods
+// moves the user's pointer in the message's room forward, never backwards.
+// returns None when the message doesn't exist, and Some(None) when the
+// pointer was already past it so there's nothing to broadcast
+pub async fn mark_read(
+    pool: &PgPool,
+    email: &str,
+    username: &str,
+    req: MarkReadRequest,
+) -> Result<Option<Option<OutgoingMessage>>, sqlx::Error> {
+    let updated = sqlx::query_as::<_, (i32, i32)>(
+        "INSERT INTO last_read (email, room_id, last_read_message_id)
+        SELECT $1, room_id, id FROM messages WHERE id = $2 AND deleted_at IS NULL
+        ON CONFLICT (email, room_id) DO UPDATE SET
+            last_read_message_id = GREATEST(last_read.last_read_message_id, EXCLUDED.last_read_message_id),
+            updated_at = CURRENT_TIMESTAMP
+        RETURNING room_id, last_read_message_id",
+    )
+    .bind(email)
+    .bind(req.message_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(updated.map(|(room_id, last_read)| {
+        (last_read == req.message_id).then(|| OutgoingMessage::ReadReceipt {
+            room_id,
+            username: username.to_string(),
+            message_id: last_read,
+        })
+    }))
+}
+
+// routes
+#[get("/unread-count")]
+pub async fn unread_count(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    query: web::Query<UnreadQuery>,
+) -> Result<HttpResponse, ChatError> {
+    let claims = authenticate(&req, &state.db_pool).await?;
+
+    let room = query.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room_id = require_room(&state.db_pool, room).await?;
+
+    let unread = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM messages
+        WHERE room_id = $1 AND deleted_at IS NULL
+        AND id > COALESCE(
+            (SELECT last_read_message_id FROM last_read WHERE email = $2 AND room_id = $1),
+            0
+        )",
+    )
+    .bind(room_id)
+    .bind(&claims.sub)
+    .fetch_one(&state.db_pool)
+    .await
+    .inspect_err(|e| tracing::error!(error = %e, room, "failed to count unread messages"))?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "room": room,
+        "unread": unread,
+    })))
+}
@@ -0,0 +1,128 @@
+// libs
+use crate::config;
+use crate::middlewares::authenticate;
+use crate::routes::chat::{AppState, ChatError};
+use actix_multipart::Multipart;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use futures_util::StreamExt as _;
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// structs
+// where uploaded files end up; swap the implementation to move them off disk
+pub trait UploadStore: Send + Sync {
+    // stores `data` under `key` and returns the url it's served from
+    fn save<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<String>>;
+
+    // whether `url` points at something this store handed out
+    fn owns_url(&self, url: &str) -> bool;
+}
+
+// files go to <STATIC_DIR>/uploads and are served by the static file handler
+pub struct DiskStore {
+    dir: PathBuf,
+    url_prefix: &'static str,
+}
+
+impl DiskStore {
+    pub fn new() -> Self {
+        Self {
+            dir: PathBuf::from(&config::get().static_dir).join("uploads"),
+            url_prefix: "/uploads/",
+        }
+    }
+}
+
+impl Default for DiskStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UploadStore for DiskStore {
+    fn save<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(self.dir.join(key), data).await?;
+            Ok(format!("{}{}", self.url_prefix, key))
+        })
+    }
+
+    fn owns_url(&self, url: &str) -> bool {
+        url.strip_prefix(self.url_prefix).is_some_and(is_upload_key)
+    }
+}
+
+// only types browsers render inertly are accepted, so nothing executable or
+// scriptable (html, svg, binaries) can be served back from our origin
+const ALLOWED_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("application/pdf", "pdf"),
+    ("text/plain", "txt"),
+];
+
+// keys are always <uuid>.<ext> as generated by upload
+fn is_upload_key(key: &str) -> bool {
+    key.split_once('.').is_some_and(|(id, ext)| {
+        Uuid::parse_str(id).is_ok() && ALLOWED_TYPES.iter().any(|(_, allowed)| *allowed == ext)
+    })
+}
+
+// routes
+#[post("/upload")]
+pub async fn upload(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, ChatError> {
+    authenticate(&req, &state.db_pool).await?;
+    let max_bytes = config::get().max_upload_bytes;
+
+    let Some(field) = payload.next().await else {
+        return Err(ChatError::BadRequest("missing_file"));
+    };
+    let mut field = field.map_err(|_| ChatError::BadRequest("invalid_upload"))?;
+
+    let extension = field
+        .content_type()
+        .and_then(|mime| {
+            ALLOWED_TYPES
+                .iter()
+                .find(|(allowed, _)| *allowed == mime.essence_str())
+        })
+        .map(|(_, extension)| *extension)
+        .ok_or(ChatError::BadRequest("unsupported_file_type"))?;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|_| ChatError::BadRequest("invalid_upload"))?;
+        if data.len() + chunk.len() > max_bytes {
+            return Err(ChatError::TooLarge("file_too_large"));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.is_empty() {
+        return Err(ChatError::BadRequest("missing_file"));
+    }
+
+    // one file per request
+    if payload.next().await.is_some() {
+        return Err(ChatError::BadRequest("too_many_files"));
+    }
+
+    let key = format!("{}.{}", Uuid::new_v4(), extension);
+    let url = state
+        .uploads
+        .save(&key, data)
+        .await
+        .inspect_err(|e| tracing::error!(error = %e, "failed to store upload"))?;
+
+    Ok(HttpResponse::Created().json(json!({ "url": url })))
+}
@@ -0,0 +1,73 @@
+// libs
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serializer;
+
+// every timestamp leaves the server as RFC 3339 in UTC with millisecond
+// precision and a `Z` suffix, e.g. 2023-05-20T15:30:00.000Z, independent of
+// how chrono's own Serialize impl happens to format it
+pub fn format(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(time))
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_some(&format(time)),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Stamped {
+        #[serde(serialize_with = "serialize")]
+        time: DateTime<Utc>,
+        #[serde(serialize_with = "option::serialize")]
+        edited_at: Option<DateTime<Utc>>,
+    }
+
+    #[test]
+    fn serializes_with_millis_and_z() {
+        // sub-millisecond digits are cut, not rounded
+        let time = Utc.with_ymd_and_hms(2023, 5, 20, 15, 30, 0).unwrap()
+            + chrono::Duration::microseconds(123_987);
+        let json = serde_json::to_string(&Stamped {
+            time,
+            edited_at: Some(time),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"time":"2023-05-20T15:30:00.123Z","edited_at":"2023-05-20T15:30:00.123Z"}"#
+        );
+    }
+
+    #[test]
+    fn serializes_missing_time_as_null() {
+        let time = Utc.with_ymd_and_hms(2023, 5, 20, 15, 30, 0).unwrap();
+        let json = serde_json::to_string(&Stamped {
+            time,
+            edited_at: None,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"time":"2023-05-20T15:30:00.000Z","edited_at":null}"#
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// libs
+use crate::config::{Config, WordFilterMode};
+use regex::{Regex, RegexBuilder};
+use std::fs;
+
+// structs
+pub struct WordFilter {
+    pattern: Regex,
+    mode: WordFilterMode,
+}
+
+// mods
+impl WordFilter {
+    // one word or phrase per line; blank lines and lines starting with # are
+    // skipped. all words are folded into a single case-insensitive regex, so
+    // a message is scanned once however long the list is
+    pub fn load(path: &str, mode: WordFilterMode) -> Result<Option<Self>, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let words: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(regex::escape)
+            .collect();
+        if words.is_empty() {
+            return Ok(None);
+        }
+
+        let pattern = RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+            .case_insensitive(true)
+            .size_limit(64 << 20)
+            .build()
+            .map_err(|e| format!("failed to compile word filter: {}", e))?;
+        Ok(Some(Self { pattern, mode }))
+    }
+
+    // None when WORD_FILTER_PATH is unset, which turns the filter off
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        match &config.word_filter_path {
+            Some(path) => Self::load(path, config.word_filter_mode),
+            None => Ok(None),
+        }
+    }
+
+    // rejects the message, or returns it with each match starred out
+    pub fn apply(&self, message: &str) -> Result<String, &'static str> {
+        if !self.pattern.is_match(message) {
+            return Ok(message.to_string());
+        }
+        match self.mode {
+            WordFilterMode::Reject => Err("blocked_content"),
+            WordFilterMode::Mask => Ok(self
+                .pattern
+                .replace_all(message, |caps: &regex::Captures| {
+                    "*".repeat(caps[0].chars().count())
+                })
+                .into_owned()),
+        }
+    }
+}